@@ -0,0 +1,82 @@
+use serde::{Deserialize, Serialize};
+
+/// Gray value to emit for each of 16 evenly spaced input grays (0, 17, 34, ... 255).
+///
+/// Values in between are interpolated, so the identity table leaves the frame untouched.
+/// Raising an entry lightens that part of the ramp on the panel, lowering it darkens it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct Calibration(pub [u8; 16]);
+
+impl Default for Calibration {
+    fn default() -> Self {
+        let mut table = [0; 16];
+        for (i, entry) in table.iter_mut().enumerate() {
+            *entry = (i * 17) as u8;
+        }
+        Self(table)
+    }
+}
+
+impl Calibration {
+    pub fn is_identity(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Expands the table to a lookup for every 8-bit gray value
+    pub fn lookup_table(&self) -> [u8; 256] {
+        let mut lut = [0; 256];
+        for (value, entry) in lut.iter_mut().enumerate() {
+            let (segment, offset) = (value / 17, value % 17);
+            let low = self.0[segment] as i32;
+            let high = self.0[(segment + 1).min(15)] as i32;
+            *entry = (low + (high - low) * offset as i32 / 17) as u8;
+        }
+        lut
+    }
+
+    pub fn apply(&self, gray: &mut [u8]) {
+        if self.is_identity() {
+            return;
+        }
+        let lut = self.lookup_table();
+        for value in gray.iter_mut() {
+            *value = lut[*value as usize];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_table_changes_nothing() {
+        let lut = Calibration::default().lookup_table();
+        assert!((0..=255).all(|value| lut[value] as usize == value));
+    }
+
+    #[test]
+    fn entries_are_hit_exactly_and_interpolated_between() {
+        let mut table = Calibration::default().0;
+        table[1] = 34;
+        table[2] = 34;
+        let lut = Calibration(table).lookup_table();
+        assert_eq!(lut[0], 0);
+        assert_eq!(lut[8], 16);
+        assert_eq!(lut[17], 34);
+        assert_eq!(lut[25], 34);
+        assert_eq!(lut[255], 255);
+    }
+
+    #[test]
+    fn apply_maps_through_the_table() {
+        let mut inverted = [0; 16];
+        for (i, entry) in inverted.iter_mut().enumerate() {
+            *entry = 255 - (i * 17) as u8;
+        }
+        let mut gray = [0, 1, 100, 255];
+        Calibration(inverted).apply(&mut gray);
+        assert_eq!(gray, [255, 254, 155, 0]);
+    }
+}
@@ -4,62 +4,262 @@ use metar::{
     CloudLayer, CloudType, Clouds, Data, Metar, SpeedUnit, WeatherCondition, WeatherIntensity,
 };
 
+use crate::config::{Config, TemperatureUnits, WindSpeedUnits};
+use crate::display::Canvas;
 use crate::render::set_font;
-use crate::{DPI, EMOJI_FONT, FONT, HEIGHT, TEMPERATURE_UNITS, WIDTH, WIND_SPEED_UNITS};
+use crate::telemetry::Telemetry;
+use crate::waveform::{Content, Zone};
 
-pub fn draw_clock(ctx: &Context, date_time: &DateTime<Local>, current_metar: Option<&Metar<'_>>) {
+#[derive(Debug, Clone, Copy)]
+struct Rect {
+    x: f64,
+    y: f64,
+    width: f64,
+    height: f64,
+}
+
+impl Rect {
+    fn new(x: f64, y: f64, width: f64, height: f64) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    fn center_x(&self) -> f64 {
+        self.x + self.width / 2.
+    }
+
+    fn center_y(&self) -> f64 {
+        self.y + self.height / 2.
+    }
+
+    fn bottom(&self) -> f64 {
+        self.y + self.height
+    }
+
+    fn zone(&self, content: Content) -> Zone {
+        Zone {
+            x: self.x,
+            y: self.y,
+            width: self.width,
+            height: self.height,
+            content,
+        }
+    }
+}
+
+/// Where the blocks below the date and time go
+struct Layout {
+    weather: Rect,
+    clouds: Rect,
+    art: Rect,
+}
+
+impl Layout {
+    fn new(canvas: &Canvas) -> Self {
+        let (width, height) = (canvas.width, canvas.height);
+        if canvas.is_portrait() {
+            // Weather and art stacked under the time
+            Self {
+                weather: Rect::new(0., height * 0.25, width * 0.85, height * 0.35),
+                clouds: Rect::new(width * 0.85, height * 0.25, width / 10., height * 0.35),
+                art: Rect::new(0., height * 0.6, width, height * 0.4),
+            }
+        } else {
+            // Weather and art side by side under the time
+            Self {
+                weather: Rect::new(0., height / 2., width / 2., height / 2.),
+                clouds: Rect::new(width / 2., height / 2., width / 16., height / 2.),
+                art: Rect::new(width / 2., height / 2., width / 2., height / 2.),
+            }
+        }
+    }
+}
+
+/// Sizes are relative to the [Canvas] so the same layout works on any panel.
+///
+/// Returns what was drawn where, so each part can be updated with a fitting waveform.
+pub fn draw_clock(
+    ctx: &Context,
+    canvas: &Canvas,
+    config: &Config,
+    date_time: &DateTime<Local>,
+    current_metar: Option<&Metar<'_>>,
+    telemetry: Option<&Telemetry>,
+) -> Vec<Zone> {
+    let layout = Layout::new(canvas);
     ctx.set_source_rgb(1.0, 1.0, 1.0);
-    ctx.rectangle(0.0, 0.0, WIDTH as f64, HEIGHT as f64);
+    ctx.rectangle(0.0, 0.0, canvas.width, canvas.height);
     ctx.fill();
     ctx.set_source_rgb(0.0, 0.0, 0.0);
 
-    // ctx.set_source_rgb(0.0, 0.0, 0.0);
-    let date_extents = draw_date(ctx, date_time.date());
-    draw_time(ctx, date_extents, &date_time);
+    let battery_warning =
+        telemetry.filter(|telemetry| telemetry.battery_needs_attention(config.kindle.low_battery));
+    // Keep the date clear of the battery on either side, so it stays centered
+    let date_margin = battery_warning.map_or(0., |_| {
+        let battery = battery_rect(canvas);
+        canvas.width - battery.x + battery_margin(canvas) * 3.
+    });
+    let date_extents = draw_date(ctx, canvas, config, date_time.date(), date_margin);
+    draw_time(ctx, canvas, config, date_extents, &date_time);
     if let Some(current_metar) = current_metar {
-        draw_current_weather(ctx, current_metar);
+        draw_current_weather(ctx, canvas, &layout, config, current_metar);
+    }
+    draw_art(ctx, canvas, &layout, config, &date_time);
+    if let Some(telemetry) = battery_warning {
+        draw_battery_warning(ctx, canvas, telemetry);
+    }
+
+    // The time is drawn starting half a line below the date
+    let time_top = date_extents.height * 1.5;
+    vec![
+        Rect::new(0., 0., canvas.width, time_top).zone(Content::Text),
+        Rect::new(0., time_top, canvas.width, layout.weather.y - time_top).zone(Content::Digits),
+        layout.weather.zone(Content::Graphics),
+        layout.clouds.zone(Content::Graphics),
+        layout.art.zone(Content::Graphics),
+    ]
+}
+
+fn battery_margin(canvas: &Canvas) -> f64 {
+    canvas.unit * 0.05
+}
+
+/// The body of the battery, in the top right corner inside the date band
+fn battery_rect(canvas: &Canvas) -> Rect {
+    let (width, height) = (canvas.unit * 0.4, canvas.unit * 0.2);
+    let margin = battery_margin(canvas);
+    Rect::new(canvas.width - width - margin * 2., margin, width, height)
+}
+
+/// A small battery with its charge level, struck through while unplugged
+fn draw_battery_warning(ctx: &Context, canvas: &Canvas, telemetry: &Telemetry) {
+    let Rect {
+        x,
+        y,
+        width,
+        height,
+    } = battery_rect(canvas);
+    let margin = battery_margin(canvas);
+    let charge = telemetry.battery_percent.unwrap_or(0) as f64 / 100.;
+    ctx.set_line_width(canvas.unit * 0.015);
+    ctx.rectangle(x, y, width, height);
+    ctx.stroke().unwrap();
+    // The terminal
+    ctx.rectangle(x + width, y + height * 0.3, margin, height * 0.4);
+    ctx.fill().unwrap();
+    ctx.rectangle(x, y, width * charge, height);
+    ctx.fill().unwrap();
+    if telemetry.charging == Some(false) {
+        // Struck through while unplugged
+        ctx.move_to(x - margin, y + height + margin);
+        ctx.line_to(x + width + margin * 2., y - margin);
+        ctx.stroke().unwrap();
     }
-    draw_art(ctx, &date_time);
 }
 
-fn draw_date(ctx: &Context, date: Date<Local>) -> TextExtents {
+/// Shrinks the date to keep `margin` clear on both sides if it has to, but returns its extents at
+/// full size so the time below stays put
+fn draw_date(
+    ctx: &Context,
+    canvas: &Canvas,
+    config: &Config,
+    date: Date<Local>,
+    margin: f64,
+) -> TextExtents {
     let date = format!("{}", date.format("%A %B %_d, %Y"));
 
-    set_font(ctx, FONT);
-    ctx.set_font_size(DPI * 0.50);
+    set_font(ctx, &config.display.font);
+    let font_size = canvas.unit * 0.50;
+    ctx.set_font_size(font_size);
     let extents = ctx.text_extents(&date).unwrap();
-    ctx.move_to((WIDTH as f64 - extents.width) / 2.0, extents.height);
+    let available = canvas.width - margin * 2.;
+    let shown = if extents.width > available {
+        ctx.set_font_size(font_size * available / extents.width);
+        ctx.text_extents(&date).unwrap()
+    } else {
+        extents
+    };
+    ctx.move_to((canvas.width - shown.width) / 2.0, extents.height);
     ctx.show_text(&date);
     ctx.stroke();
     extents
 }
 
-fn draw_time(ctx: &Context, date_extents: TextExtents, date_time: &DateTime<Local>) {
+fn draw_time(
+    ctx: &Context,
+    canvas: &Canvas,
+    config: &Config,
+    date_extents: TextExtents,
+    date_time: &DateTime<Local>,
+) {
     let (_, hour12) = date_time.hour12();
     let time = format!("{}{}", hour12, date_time.format(":%M %p"));
 
-    set_font(ctx, FONT);
-    ctx.set_font_size(DPI * 1.5);
+    set_font(ctx, &config.display.font);
+    ctx.set_font_size(canvas.unit * 1.5);
     let time_extents = ctx.text_extents(&time).unwrap();
     ctx.move_to(
-        (WIDTH as f64 - time_extents.width) / 2.0,
+        (canvas.width - time_extents.width) / 2.0,
         date_extents.height * 1.5 + time_extents.height,
     );
     ctx.show_text(&time);
 }
 
-fn draw_current_weather(ctx: &Context, current_metar: &Metar<'_>) {
-    use uom::fmt::DisplayStyle;
+fn format_temperature(temp_celsius: f32, units: TemperatureUnits) -> String {
+    use uom::fmt::DisplayStyle::Abbreviation;
+    use uom::si::f32::ThermodynamicTemperature;
+    use uom::si::thermodynamic_temperature::{degree_celsius, degree_fahrenheit, kelvin};
+
+    let temp = ThermodynamicTemperature::new::<degree_celsius>(temp_celsius);
+    match units {
+        TemperatureUnits::Fahrenheit => format!(
+            "{:.1}",
+            temp.into_format_args(degree_fahrenheit, Abbreviation)
+        ),
+        TemperatureUnits::Celsius => {
+            format!("{:.1}", temp.into_format_args(degree_celsius, Abbreviation))
+        }
+        TemperatureUnits::Kelvin => format!("{:.1}", temp.into_format_args(kelvin, Abbreviation)),
+    }
+}
+
+fn format_wind_speed(velocity: uom::si::f32::Velocity, units: WindSpeedUnits) -> String {
+    use uom::fmt::DisplayStyle::Abbreviation;
+    use uom::si::velocity::{kilometer_per_hour, knot, meter_per_second, mile_per_hour};
+
+    match units {
+        WindSpeedUnits::MilesPerHour => format!(
+            "{:.1}",
+            velocity.into_format_args(mile_per_hour, Abbreviation)
+        ),
+        WindSpeedUnits::KilometersPerHour => format!(
+            "{:.1}",
+            velocity.into_format_args(kilometer_per_hour, Abbreviation)
+        ),
+        WindSpeedUnits::MetersPerSecond => format!(
+            "{:.1}",
+            velocity.into_format_args(meter_per_second, Abbreviation)
+        ),
+        WindSpeedUnits::Knots => format!("{:.1}", velocity.into_format_args(knot, Abbreviation)),
+    }
+}
+
+fn draw_current_weather(
+    ctx: &Context,
+    canvas: &Canvas,
+    layout: &Layout,
+    config: &Config,
+    current_metar: &Metar<'_>,
+) {
+    let (weather, clouds, unit) = (&layout.weather, &layout.clouds, canvas.unit);
 
     let mut concise_observation = String::new();
     if let Data::Known(temp) = &current_metar.temperature {
-        use uom::si::f32::ThermodynamicTemperature;
-        use uom::si::thermodynamic_temperature::degree_celsius;
-        let temp_celsius = ThermodynamicTemperature::new::<degree_celsius>(*temp as f32);
-        concise_observation += &format!(
-            "{:.1}",
-            temp_celsius.into_format_args(TEMPERATURE_UNITS, DisplayStyle::Abbreviation)
-        );
+        concise_observation += &format_temperature(*temp as f32, config.weather.temperature_units);
     }
     if let Data::Known(wind_speed) = &current_metar.wind.speed {
         if let Data::Known(_) = &current_metar.temperature {
@@ -73,18 +273,15 @@ fn draw_current_weather(ctx: &Context, current_metar: &Metar<'_>) {
                 Velocity::new::<meter_per_second>(wind_speed.speed as f32)
             }
         };
-        concise_observation += &format!(
-            "{:.1}",
-            velocity.into_format_args(WIND_SPEED_UNITS, DisplayStyle::Abbreviation)
-        );
+        concise_observation += &format_wind_speed(velocity, config.weather.wind_speed_units);
     }
 
-    set_font(ctx, FONT);
-    ctx.set_font_size(DPI * 0.45);
+    set_font(ctx, &config.display.font);
+    ctx.set_font_size(unit * 0.45);
     let extents = ctx.text_extents(&concise_observation).unwrap();
     ctx.move_to(
-        WIDTH as f64 * 0.25 - extents.width * 0.5,
-        HEIGHT as f64 - (extents.height + extents.y_bearing) * 0.5,
+        weather.center_x() - extents.width * 0.5,
+        weather.bottom() - (extents.height + extents.y_bearing) * 0.5,
     );
     ctx.show_text(&concise_observation);
 
@@ -95,10 +292,10 @@ fn draw_current_weather(ctx: &Context, current_metar: &Metar<'_>) {
             "\u{263c}".to_owned() // sunny
         }
         Data::Known(Clouds::CloudLayers) => {
-            let gradient_width = WIDTH as f64 / 16.;
-            let gradient_height = HEIGHT as f64 / 2.;
-            let gradient_x = WIDTH as f64 / 2.;
-            let gradient_y = HEIGHT as f64 / 2.;
+            let gradient_width = clouds.width;
+            let gradient_height = clouds.height;
+            let gradient_x = clouds.x;
+            let gradient_y = clouds.y;
             ctx.set_source_rgb(0.0, 0.0, 0.0);
             ctx.rectangle(gradient_x, gradient_y, gradient_width, gradient_height);
             ctx.fill();
@@ -122,8 +319,8 @@ fn draw_current_weather(ctx: &Context, current_metar: &Metar<'_>) {
             let max_height =
                 layers.iter().max_by_key(|l| l.1).map(|l| l.1).unwrap_or(0) as f64 * 1.05;
             ctx.set_source_rgb(1.0, 1.0, 1.0);
-            set_font(ctx, FONT);
-            ctx.set_font_size(DPI * 0.1);
+            set_font(ctx, &config.display.font);
+            ctx.set_font_size(unit * 0.1);
             for layer in layers {
                 let level = layer.0 as f64 / 8.;
                 let text = format!("{} ft", layer.1 * 100);
@@ -140,14 +337,14 @@ fn draw_current_weather(ctx: &Context, current_metar: &Metar<'_>) {
                     ],
                     0.,
                 );
-                ctx.set_line_width(5.);
+                ctx.set_line_width(unit / 30.);
                 ctx.move_to(
                     gradient_x,
-                    gradient_y + (1. - layer.1 as f64 / max_height) * gradient_height + 2.0,
+                    gradient_y + (1. - layer.1 as f64 / max_height) * gradient_height + unit / 75.,
                 );
                 ctx.line_to(
                     gradient_x + gradient_width,
-                    gradient_y + (1. - layer.1 as f64 / max_height) * gradient_height + 5.,
+                    gradient_y + (1. - layer.1 as f64 / max_height) * gradient_height + unit / 30.,
                 );
                 ctx.stroke();
             }
@@ -184,43 +381,59 @@ fn draw_current_weather(ctx: &Context, current_metar: &Metar<'_>) {
             }
         }
     }
-    set_font(ctx, EMOJI_FONT);
+    set_font(ctx, &config.display.emoji_font);
     ctx.set_source_rgb(0.0, 0.0, 0.0);
-    let mut initial_scale = DPI * 1.5;
+    let mut initial_scale = unit * 1.5;
 
     let mut extents;
     while {
         // Silly shrink to fit, I'm not sure how to do it the right way
         ctx.set_font_size(initial_scale);
         extents = ctx.text_extents(&weather_emojis).unwrap();
-        extents.width > WIDTH as f64 / 2.0
+        extents.width > weather.width
     } {
         initial_scale *= 0.9;
     }
     ctx.move_to(
-        WIDTH as f64 * 0.25 - (extents.x_bearing + extents.width) * 0.5,
-        HEIGHT as f64 * 0.5 + extents.height,
+        weather.center_x() - (extents.x_bearing + extents.width) * 0.5,
+        weather.y + extents.height,
     );
     ctx.show_text(&weather_emojis);
 }
 
-fn draw_art(ctx: &Context, date_time: &DateTime<Local>) {
+fn draw_art(
+    ctx: &Context,
+    canvas: &Canvas,
+    layout: &Layout,
+    config: &Config,
+    date_time: &DateTime<Local>,
+) {
+    let art = &layout.art;
     let (_, hour12) = date_time.hour12();
     let surface = crate::art::get_surface_for_hour12(hour12);
+    // The art is sized for the Kindle DX, only shrink it further if the block is too small
+    let scale = (canvas.unit / 150.)
+        .min(art.width / surface.width() as f64)
+        .min(art.height / surface.height() as f64);
+    ctx.save().unwrap();
+    ctx.translate(art.center_x(), art.center_y());
+    ctx.scale(scale, scale);
     ctx.set_source_surface(
         &surface,
-        WIDTH as f64 * 0.75 - surface.width() as f64 * 0.5,
-        HEIGHT as f64 * 0.75 - surface.height() as f64 * 0.5,
-    ).unwrap();
+        surface.width() as f64 * -0.5,
+        surface.height() as f64 * -0.5,
+    )
+    .unwrap();
     ctx.paint().unwrap();
+    ctx.restore().unwrap();
     ctx.set_source_rgb(0.0, 0.0, 0.0);
-    set_font(ctx, FONT);
-    ctx.set_font_size(DPI * 0.25);
+    set_font(ctx, &config.display.font);
+    ctx.set_font_size(canvas.unit * 0.25);
     let art_name = crate::art::get_name_for_hour12(hour12);
     let extents = ctx.text_extents(&art_name).unwrap();
     ctx.move_to(
-        WIDTH as f64 * 0.75 - extents.width * 0.5,
-        HEIGHT as f64 - (extents.height + extents.y_bearing),
+        art.center_x() - extents.width * 0.5,
+        art.bottom() - (extents.height + extents.y_bearing),
     );
     ctx.show_text(&art_name);
 }
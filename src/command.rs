@@ -0,0 +1,368 @@
+use chrono::prelude::*;
+
+use std::borrow::Cow;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use crate::waveform::Waveform;
+
+const EIPS: &str = "/usr/sbin/eips";
+const AMIXER: &str = "/usr/bin/amixer";
+const APLAY: &str = "/usr/bin/aplay";
+
+/// A program and its arguments, quoted for the Kindle's shell when run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Command {
+    program: String,
+    args: Vec<String>,
+}
+
+impl Command {
+    pub fn new(program: impl Into<String>) -> Self {
+        Self {
+            program: program.into(),
+            args: vec![],
+        }
+    }
+
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Adds the argument only if `condition` holds, for flags
+    pub fn arg_if(self, condition: bool, arg: impl Into<String>) -> Self {
+        if condition {
+            self.arg(arg)
+        } else {
+            self
+        }
+    }
+
+    /// The command line for `/bin/sh`, with every word quoted as needed
+    pub fn to_shell(&self) -> String {
+        let mut line = quote(&self.program).into_owned();
+        for arg in &self.args {
+            line.push(' ');
+            line.push_str(&quote(arg));
+        }
+        line
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_shell())
+    }
+}
+
+/// Wraps a word in single quotes unless it only has characters the shell leaves alone
+fn quote(word: &str) -> Cow<'_, str> {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "_-./:=,%+@".contains(c);
+    if !word.is_empty() && word.chars().all(plain) {
+        Cow::Borrowed(word)
+    } else {
+        Cow::Owned(format!("'{}'", word.replace('\'', r"'\''")))
+    }
+}
+
+/// Runs a script with `/bin/sh`, for pipelines and globs
+pub fn sh(script: &str) -> Command {
+    Command::new("/bin/sh").arg("-c").arg(script)
+}
+
+/// Draws a PNG, at (x, y) if given and otherwise over the whole screen
+pub fn eips_image(
+    path: &Path,
+    full_update: bool,
+    position: Option<(usize, usize)>,
+    waveform: Waveform,
+) -> Command {
+    let mut command = Command::new(EIPS)
+        .arg_if(full_update, "-f")
+        .arg("-g")
+        .arg(path.to_string_lossy());
+    if let Some((x, y)) = position {
+        command = command
+            .arg("-x")
+            .arg(x.to_string())
+            .arg("-y")
+            .arg(y.to_string());
+    }
+    // Without -w the driver picks the waveform itself
+    match waveform.eips_name() {
+        Some(name) => command.arg("-w").arg(name),
+        None => command,
+    }
+}
+
+/// Blanks the screen to white with a flash
+pub fn eips_clear() -> Command {
+    Command::new(EIPS).arg("-c").arg("-f")
+}
+
+/// Prints the framebuffer info, including its resolution
+pub fn eips_info() -> Command {
+    Command::new(EIPS).arg("-i")
+}
+
+pub fn fbink_image(
+    fbink: &str,
+    path: &Path,
+    full_update: bool,
+    position: Option<(usize, usize)>,
+    waveform: Waveform,
+) -> Command {
+    let mut image = format!("file={}", path.display());
+    if let Some((x, y)) = position {
+        image.push_str(&format!(",x={},y={}", x, y));
+    }
+    Command::new(fbink)
+        .arg_if(full_update, "-f")
+        .arg("-q")
+        .arg("-g")
+        .arg(image)
+        .arg("-W")
+        .arg(waveform.fbink_name())
+}
+
+pub fn fbink_clear(fbink: &str) -> Command {
+    Command::new(fbink).arg("-q").arg("-k").arg("-f")
+}
+
+pub fn amixer_set_master_volume(volume: u8) -> Command {
+    Command::new(AMIXER)
+        .arg("set")
+        .arg("Master")
+        .arg(format!("{}%", volume))
+}
+
+/// Plays a WAV file without waiting for the sound device
+pub fn aplay_nonblocking(path: &Path) -> Command {
+    Command::new(APLAY)
+        .arg("-q")
+        .arg("-N")
+        .arg(path.to_string_lossy())
+}
+
+pub fn cat(path: &Path) -> Command {
+    Command::new("cat").arg(path.to_string_lossy())
+}
+
+/// Copies `count` blocks of `block_size` bytes from the start of a file to stdout
+pub fn dd(path: &Path, block_size: usize, count: usize) -> Command {
+    Command::new("dd")
+        .arg(format!("if={}", path.display()))
+        .arg(format!("bs={}", block_size))
+        .arg(format!("count={}", count))
+}
+
+/// Sets a property of one of the Kindle's services over its LIPC bus
+pub fn lipc_set_prop(publisher: &str, property: &str, value: &str) -> Command {
+    Command::new("lipc-set-prop")
+        .arg(publisher)
+        .arg(property)
+        .arg(value)
+}
+
+pub fn killall(signal: &str, process: &str) -> Command {
+    Command::new("killall")
+        .arg(format!("-{}", signal))
+        .arg(process)
+}
+
+pub fn framework_stop() -> Command {
+    framework("stop")
+}
+
+pub fn framework_start() -> Command {
+    framework("start")
+}
+
+/// Older firmwares have an init script for the framework, newer ones an upstart job
+fn framework(action: &str) -> Command {
+    sh(&format!(
+        "if [ -x /etc/init.d/framework ]; then /etc/init.d/framework {0}; else {0} framework; fi",
+        action
+    ))
+}
+
+/// Prints the battery level and charging state, panel temperature, uptime and free space in
+/// `/dev/shm` as `name=value` lines, leaving out the value of anything the Kindle doesn't have
+pub fn telemetry_report() -> Command {
+    sh(concat!(
+        "echo battery=$(lipc-get-prop com.lab126.powerd battLevel 2>/dev/null); ",
+        "echo charging=$(lipc-get-prop com.lab126.powerd isCharging 2>/dev/null); ",
+        "echo temperature=$(cat /sys/devices/platform/*epdc*/temperature ",
+        "/sys/devices/platform/*/*epdc*/temperature /proc/eink_fb/temperature 2>/dev/null | head -n 1); ",
+        "echo uptime=$(cut -d ' ' -f 1 /proc/uptime); ",
+        "echo shm_free=$(df -k /dev/shm | tail -n 1 | awk '{ print $(NF - 2) }')"
+    ))
+}
+
+/// Creates the directory if needed and prints the MD5 of every file in it, as `md5sum` does
+pub fn md5sum_directory(directory: &Path) -> Command {
+    let directory = quote(&directory.to_string_lossy()).into_owned();
+    sh(&format!("mkdir -p {0} && cd {0} && md5sum *", directory))
+}
+
+/// Prints the time as seconds since the epoch
+pub fn date_epoch() -> Command {
+    Command::new("date").arg("+%s")
+}
+
+/// Sets the time, in the MMDDhhmmYYYY.ss form even old BusyBox understands
+pub fn date_set(time: DateTime<Utc>) -> Command {
+    Command::new("date")
+        .arg("-u")
+        .arg(time.format("%m%d%H%M%Y.%S").to_string())
+}
+
+/// Saves the system time to the hardware clock, so it survives a reboot
+pub fn hwclock_save() -> Command {
+    Command::new("hwclock").arg("-u").arg("-w")
+}
+
+/// What a finished command returned
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Output {
+    /// Exit status, or -1 if the command was killed by a signal
+    pub status: i32,
+    /// Raw bytes, some commands print binary data
+    pub stdout: Vec<u8>,
+    pub stderr: String,
+}
+
+impl Output {
+    pub fn success(&self) -> bool {
+        self.status == 0
+    }
+
+    /// What the command printed to stdout, as text
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.stdout).into_owned()
+    }
+}
+
+#[derive(Debug)]
+pub enum CommandError {
+    /// The command could not be run or its output could not be read
+    Io(io::Error),
+    /// The command ran but exited with a non-zero status
+    Failed {
+        command: String,
+        status: i32,
+        stderr: String,
+    },
+    /// The command ran but printed something that doesn't make sense, so the session is fine
+    Unexpected(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Io(err) => write!(f, "{}", err),
+            CommandError::Failed {
+                command,
+                status,
+                stderr,
+            } => write!(
+                f,
+                "`{}` exited with status {}: {}",
+                command,
+                status,
+                stderr.trim()
+            ),
+            CommandError::Unexpected(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CommandError::Io(err) => Some(err),
+            CommandError::Failed { .. } | CommandError::Unexpected(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for CommandError {
+    fn from(err: io::Error) -> Self {
+        CommandError::Io(err)
+    }
+}
+
+impl From<ssh2::Error> for CommandError {
+    fn from(err: ssh2::Error) -> Self {
+        CommandError::Io(err.into())
+    }
+}
+
+/// Display sinks report plain I/O errors, with a failed command kept inside as the cause
+impl From<CommandError> for io::Error {
+    fn from(err: CommandError) -> Self {
+        match err {
+            CommandError::Io(err) => err,
+            failed => io::Error::other(failed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_words_are_not_quoted() {
+        assert_eq!(
+            quote("/dev/shm/eink-clock/a_b.png"),
+            "/dev/shm/eink-clock/a_b.png"
+        );
+        assert_eq!(quote("file=x.png,x=8,y=16"), "file=x.png,x=8,y=16");
+        assert_eq!(quote("50%"), "50%");
+    }
+
+    #[test]
+    fn spaces_are_quoted() {
+        assert_eq!(quote("two words"), "'two words'");
+    }
+
+    #[test]
+    fn single_quotes_are_closed_escaped_and_reopened() {
+        assert_eq!(quote("it's"), r"'it'\''s'");
+    }
+
+    #[test]
+    fn empty_word_is_quoted() {
+        assert_eq!(quote(""), "''");
+    }
+
+    #[test]
+    fn tilde_is_quoted() {
+        // The shell would expand it to a home directory otherwise
+        assert_eq!(quote("~"), "'~'");
+    }
+
+    #[test]
+    fn md5sum_directory_quotes_the_path_inside_the_script() {
+        let command = md5sum_directory(Path::new("/mnt/us/my clock"));
+        assert_eq!(
+            command.to_shell(),
+            r"/bin/sh -c 'mkdir -p '\''/mnt/us/my clock'\'' && cd '\''/mnt/us/my clock'\'' && md5sum *'"
+        );
+    }
+
+    #[test]
+    fn command_line_quotes_each_word() {
+        let command = Command::new("echo").arg("a b").arg("c").arg("");
+        assert_eq!(command.to_shell(), "echo 'a b' c ''");
+    }
+
+    #[test]
+    fn date_set_uses_busybox_format() {
+        let time = Utc.ymd(2026, 10, 18).and_hms(9, 5, 7);
+        assert_eq!(date_set(time).to_shell(), "date -u 101809052026.07");
+    }
+}
@@ -0,0 +1,452 @@
+use serde::{Deserialize, Serialize};
+
+use crate::calibration::Calibration;
+use crate::display::{DisplayProfile, Model, Orientation};
+use crate::dither::Dithering;
+use crate::refresh::RefreshConfig;
+use crate::secrets::{Secret, Secrets, SecretsConfig};
+use crate::sink::OutputConfig;
+use crate::waveform::WaveformOverrides;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+pub const DEFAULT_CONFIG_PATH: &str = "eink-clock.toml";
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub display: DisplayConfig,
+    pub refresh: RefreshConfig,
+    pub output: OutputConfig,
+    pub weather: WeatherConfig,
+    pub network: NetworkConfig,
+    pub kindle: KindleConfig,
+    pub secrets: SecretsConfig,
+}
+
+/// Everything except fonts comes from the profile unless overridden here
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DisplayConfig {
+    pub profile: Model,
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    pub dpi: Option<f64>,
+    pub orientation: Option<Orientation>,
+    pub gray_depth: Option<u8>,
+    pub dithering: Dithering,
+    /// Corrects the panel's gray response, see the `test-pattern` command
+    pub calibration: Calibration,
+    /// Replaces the profile's waveform for digits, text or graphics
+    pub waveforms: WaveformOverrides,
+    pub font: String,
+    pub emoji_font: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WeatherConfig {
+    /// ICAO code of the METAR station
+    pub station: String,
+    pub temperature_units: TemperatureUnits,
+    pub wind_speed_units: WindSpeedUnits,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TemperatureUnits {
+    Fahrenheit,
+    Celsius,
+    Kelvin,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WindSpeedUnits {
+    MilesPerHour,
+    KilometersPerHour,
+    MetersPerSecond,
+    Knots,
+}
+
+/// How to log in to the Kindle over SSH
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SshAuth {
+    Password,
+    /// The private key file in `private_key`
+    Key,
+    /// Whatever keys the running ssh-agent holds
+    Agent,
+}
+
+/// What to do with the Kindle's own user interface while the clock runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Framework {
+    /// Leave it running, it may draw over the clock now and then
+    Leave,
+    /// Pause it, quick to resume
+    Suspend,
+    /// Stop it entirely, freeing its memory
+    Stop,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// Address assigned to this device on the usbnetwork link
+    pub pi_ip_address: IpAddr,
+    /// Name of the interface the Kindle shows up as
+    pub interface: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KindleConfig {
+    pub ip_address: IpAddr,
+    pub ssh_port: u16,
+    pub username: String,
+    pub auth: SshAuth,
+    /// Overridden by the `kindle_password` secret, if set
+    pub password: Secret,
+    /// Private key for `key` auth
+    pub private_key: Option<PathBuf>,
+    /// Where the Kindle's host key is recorded the first time it is seen
+    pub known_hosts: PathBuf,
+    /// Connect even if the Kindle's host key no longer matches the recorded one, and record the new one
+    pub accept_changed_host_key: bool,
+    /// TCP connect timeout in milliseconds
+    pub connect_timeout: u64,
+    /// Milliseconds any single step on the Kindle, such as a command or upload, may take
+    pub command_timeout: u64,
+    /// Seconds between SSH keepalives on the session kept open between updates, 0 for none
+    pub keepalive_interval: u32,
+    /// Put back as it was when eink-clock shuts down
+    pub framework: Framework,
+    /// Keep the screensaver from drawing over the clock and the Kindle from going to sleep,
+    /// until eink-clock shuts down
+    pub prevent_screensaver: bool,
+    /// Battery percentage at which the clock shows a warning
+    pub low_battery: u8,
+    /// Set the Kindle's clock from this machine's after connecting
+    pub sync_clock: bool,
+    /// Seconds the Kindle's clock may be off before it is set
+    pub max_clock_drift: u32,
+    pub vendor_id: u16,
+    pub product_id: u16,
+}
+
+impl Default for DisplayConfig {
+    fn default() -> Self {
+        Self {
+            profile: Model::KindleDx,
+            width: None,
+            height: None,
+            dpi: None,
+            orientation: None,
+            gray_depth: None,
+            dithering: Dithering::None,
+            calibration: Calibration::default(),
+            waveforms: WaveformOverrides::default(),
+            font: "Inter".to_owned(),
+            emoji_font: "OpenMoji".to_owned(),
+        }
+    }
+}
+
+impl DisplayConfig {
+    pub fn profile(&self) -> DisplayProfile {
+        let defaults = self.profile.profile();
+        DisplayProfile {
+            width: self.width.unwrap_or(defaults.width),
+            height: self.height.unwrap_or(defaults.height),
+            dpi: self.dpi.unwrap_or(defaults.dpi),
+            orientation: self.orientation.unwrap_or(defaults.orientation),
+            gray_depth: self.gray_depth.unwrap_or(defaults.gray_depth),
+            waveforms: self.waveforms.apply(defaults.waveforms),
+        }
+    }
+}
+
+impl Default for WeatherConfig {
+    fn default() -> Self {
+        Self {
+            station: "KTPA".to_owned(),
+            temperature_units: TemperatureUnits::Fahrenheit,
+            wind_speed_units: WindSpeedUnits::MilesPerHour,
+        }
+    }
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            pi_ip_address: IpAddr::V4(Ipv4Addr::new(192, 168, 2, 1)),
+            interface: "usb0".to_owned(),
+        }
+    }
+}
+
+impl KindleConfig {
+    /// Whether a sink built for this config can take on `other` as it is, because they only
+    /// differ in settings that don't affect how the Kindle is reached or taken over
+    pub fn same_session(&self, other: &KindleConfig) -> bool {
+        let adjusted = KindleConfig {
+            command_timeout: other.command_timeout,
+            keepalive_interval: other.keepalive_interval,
+            low_battery: other.low_battery,
+            sync_clock: other.sync_clock,
+            max_clock_drift: other.max_clock_drift,
+            ..self.clone()
+        };
+        adjusted == *other
+    }
+}
+
+impl Default for KindleConfig {
+    fn default() -> Self {
+        Self {
+            ip_address: IpAddr::V4(Ipv4Addr::new(192, 168, 2, 2)),
+            ssh_port: 22,
+            username: "root".to_owned(),
+            auth: SshAuth::Password,
+            password: Secret::new("root"),
+            private_key: None,
+            known_hosts: PathBuf::from("kindle_known_hosts"),
+            accept_changed_host_key: false,
+            connect_timeout: 1000,
+            command_timeout: 10000,
+            keepalive_interval: 30,
+            framework: Framework::Leave,
+            prevent_screensaver: true,
+            low_battery: 20,
+            sync_clock: true,
+            max_clock_drift: 2,
+            vendor_id: 0x0525,
+            product_id: 0xa4a2,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(err) => write!(f, "could not read config: {}", err),
+            ConfigError::Parse(err) => write!(f, "could not parse config: {}", err),
+            ConfigError::Invalid(reason) => write!(f, "invalid config: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let raw = std::fs::read_to_string(path).map_err(ConfigError::Io)?;
+        let mut config: Self = toml::from_str(&raw).map_err(ConfigError::Parse)?;
+        config.resolve_secrets()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Replaces secrets in the config with ones from the environment, systemd or the secrets file
+    fn resolve_secrets(&mut self) -> Result<(), ConfigError> {
+        let secrets = Secrets::load(&self.secrets)?;
+        if let Some(password) = secrets.get("kindle_password")? {
+            self.kindle.password = password;
+        }
+        Ok(())
+    }
+
+    /// Like [Config::load], but a missing file at the default path is not an error
+    pub fn load_or_default(path: &Path, explicit: bool) -> Result<Self, ConfigError> {
+        match Self::load(path) {
+            Err(ConfigError::Io(err))
+                if !explicit && err.kind() == std::io::ErrorKind::NotFound =>
+            {
+                warn!("no config at {}, falling back to defaults", path.display());
+                let mut config = Self::default();
+                config.resolve_secrets()?;
+                Ok(config)
+            }
+            other => other,
+        }
+    }
+
+    /// Catches values that parse fine but would break rendering or networking
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |reason: &str| Err(ConfigError::Invalid(reason.to_owned()));
+        let profile = self.display.profile();
+        if profile.width == 0 || profile.height == 0 {
+            return invalid("display width and height must be non-zero");
+        }
+        if profile.dpi.is_nan() || profile.dpi <= 0.0 {
+            return invalid("display dpi must be positive");
+        }
+        if ![1, 2, 4, 8].contains(&profile.gray_depth) {
+            return invalid("display gray depth must be 1, 2, 4 or 8 bits");
+        }
+        if self.weather.station.len() != 4
+            || !self
+                .weather
+                .station
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric())
+        {
+            return invalid("weather station must be a 4 character ICAO code");
+        }
+        if !(0.0..=1.0).contains(&self.refresh.max_partial_fraction)
+            || !(0.0..=1.0).contains(&self.refresh.flash_changed_fraction)
+            || !(0.0..=1.0).contains(&self.refresh.max_mismatch)
+        {
+            return invalid("refresh fractions must be between 0 and 1");
+        }
+        if matches!(self.refresh.cleanse_hour, Some(hour) if hour > 23) {
+            return invalid("refresh cleanse hour must be between 0 and 23");
+        }
+        if self.network.interface.is_empty() {
+            return invalid("network interface must be set");
+        }
+        if self.kindle.connect_timeout == 0 || self.kindle.command_timeout == 0 {
+            return invalid("kindle timeouts must be non-zero");
+        }
+        if self.kindle.low_battery > 100 {
+            return invalid("kindle low battery level must be a percentage");
+        }
+        if self.kindle.auth == SshAuth::Key && self.kindle.private_key.is_none() {
+            return invalid("kindle private key must be set for key auth");
+        }
+        Ok(())
+    }
+
+    /// Human-readable list of every setting that differs between the two configs
+    pub fn diff(&self, other: &Self) -> Vec<String> {
+        let (old, new) = (flatten(self), flatten(other));
+        let mut changes = vec![];
+        for (key, old_value) in &old {
+            match new.get(key) {
+                Some(new_value) if new_value != old_value => {
+                    changes.push(format!("{}: {} -> {}", key, old_value, new_value))
+                }
+                None => changes.push(format!("{}: {} -> (unset)", key, old_value)),
+                _ => {}
+            }
+        }
+        for (key, new_value) in &new {
+            if !old.contains_key(key) {
+                changes.push(format!("{}: (unset) -> {}", key, new_value));
+            }
+        }
+        changes
+    }
+}
+
+/// Dotted key to displayed value, with secrets redacted so the result can be logged
+fn flatten(config: &Config) -> BTreeMap<String, String> {
+    fn walk(prefix: &str, value: &toml::Value, out: &mut BTreeMap<String, String>) {
+        match value {
+            toml::Value::Table(table) => {
+                for (key, value) in table {
+                    let key = if prefix.is_empty() {
+                        key.clone()
+                    } else {
+                        format!("{}.{}", prefix, key)
+                    };
+                    walk(&key, value, out);
+                }
+            }
+            _ if prefix.ends_with("password") => {
+                out.insert(prefix.to_owned(), "<redacted>".to_owned());
+            }
+            other => {
+                out.insert(prefix.to_owned(), other.to_string());
+            }
+        }
+    }
+    let mut out = BTreeMap::new();
+    if let Ok(value) = toml::Value::try_from(config) {
+        walk("", &value, &mut out);
+    }
+    out
+}
+
+/// Where the running [Config] came from, so it can be reloaded in place
+pub struct ConfigSource {
+    path: PathBuf,
+    explicit: bool,
+    modified: Option<SystemTime>,
+}
+
+impl ConfigSource {
+    pub fn new(path: &Path, explicit: bool) -> Self {
+        Self {
+            path: path.to_owned(),
+            explicit,
+            modified: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn load(&mut self) -> Result<Config, ConfigError> {
+        self.modified = modified_time(&self.path);
+        Config::load_or_default(&self.path, self.explicit)
+    }
+
+    /// Whether the file was modified, created or removed since it was last loaded
+    pub fn has_changed(&self) -> bool {
+        modified_time(&self.path) != self.modified
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clock_and_battery_settings_keep_the_session() {
+        let kindle = KindleConfig::default();
+        let adjusted = KindleConfig {
+            low_battery: 10,
+            sync_clock: false,
+            max_clock_drift: 30,
+            ..kindle.clone()
+        };
+        assert!(kindle.same_session(&adjusted));
+    }
+
+    #[test]
+    fn connection_and_takeover_settings_need_a_new_session() {
+        let kindle = KindleConfig::default();
+        let moved = KindleConfig {
+            ssh_port: 2222,
+            ..kindle.clone()
+        };
+        let stopped = KindleConfig {
+            framework: Framework::Stop,
+            ..kindle.clone()
+        };
+        assert!(!kindle.same_session(&moved));
+        assert!(!kindle.same_session(&stopped));
+    }
+}
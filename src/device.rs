@@ -0,0 +1,508 @@
+use chrono::prelude::*;
+use rtnetlink::Handle;
+use tokio::runtime;
+
+use std::fs;
+use std::io;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::audio::play_audio_for_hour;
+use crate::config::Config;
+use crate::display::{DetectedGeometry, Orientation};
+use crate::frame::{Frame, Update};
+use crate::refresh::{RefreshConfig, RefreshState};
+use crate::sink::{self, DisplaySink};
+use crate::telemetry::Telemetry;
+use crate::waveform::{Content, Waveforms};
+
+enum Job {
+    Reconfigure(Box<Config>),
+    Update {
+        now: DateTime<Local>,
+        frame: Frame,
+    },
+    /// Shows the frame with a flash, replying once it is on the display
+    Show {
+        frame: Frame,
+        done: Sender<io::Result<()>>,
+    },
+}
+
+/// Owns the display sink on a thread of its own, so slow pushes never hold up the runtime
+pub struct Device {
+    jobs: Sender<Job>,
+    /// Dropped to tell the worker not to wait for the minute of a queued update
+    stop: Sender<()>,
+    detected_geometry: Arc<Mutex<Option<DetectedGeometry>>>,
+    telemetry: Arc<Mutex<Option<Telemetry>>>,
+    worker: JoinHandle<()>,
+}
+
+impl Device {
+    /// Must be called from within the runtime, which the worker uses for the async parts of connecting
+    pub fn spawn(config: &Config, handle: Handle) -> Self {
+        let (jobs, receiver) = mpsc::channel();
+        let (stop, stopped) = mpsc::channel();
+        let detected_geometry = Arc::new(Mutex::new(None));
+        let telemetry = Arc::new(Mutex::new(None));
+        let worker = {
+            let config = config.clone();
+            let runtime = runtime::Handle::current();
+            let detected_geometry = detected_geometry.clone();
+            let telemetry = telemetry.clone();
+            thread::Builder::new()
+                .name("device".to_owned())
+                .spawn(move || {
+                    Worker {
+                        sink: sink::from_config(&config, &handle),
+                        config,
+                        handle,
+                        runtime,
+                        detected_geometry,
+                        telemetry,
+                        refresh_state: RefreshState::default(),
+                        stopped,
+                    }
+                    .run(receiver)
+                })
+                .expect("failed to start device worker")
+        };
+        Self {
+            jobs,
+            stop,
+            detected_geometry,
+            telemetry,
+            worker,
+        }
+    }
+
+    fn send(&self, job: Job) {
+        // The worker only stops by panicking, which should take the whole clock down with it
+        self.jobs.send(job).expect("device worker stopped");
+    }
+
+    pub fn reconfigure(&self, config: &Config) {
+        self.send(Job::Reconfigure(Box::new(config.clone())));
+    }
+
+    /// Queues the frame for the given minute and returns straight away, the worker uploads it
+    /// right away and draws it once that minute comes
+    pub fn update(&self, now: DateTime<Local>, frame: Frame) {
+        self.send(Job::Update { now, frame });
+    }
+
+    /// Shows the frame with a flash and waits until it is on the display
+    pub fn show(&self, frame: Frame) -> io::Result<()> {
+        let (done, result) = mpsc::channel();
+        self.send(Job::Show { frame, done });
+        tokio::task::block_in_place(|| result.recv().expect("device worker stopped"))
+    }
+
+    /// Framebuffer geometry reported by the display, once it has been connected to
+    pub fn detected_geometry(&self) -> Option<DetectedGeometry> {
+        *self.detected_geometry.lock().unwrap()
+    }
+
+    /// How the display device was doing when last asked
+    pub fn telemetry(&self) -> Option<Telemetry> {
+        self.telemetry.lock().unwrap().clone()
+    }
+
+    /// Drops an update still waiting for its minute, finishes other queued jobs, then hands the
+    /// display back and closes it
+    pub fn finish(self) {
+        let Self {
+            jobs, stop, worker, ..
+        } = self;
+        drop(stop);
+        drop(jobs);
+        tokio::task::block_in_place(|| worker.join()).expect("device worker panicked");
+    }
+}
+
+struct Worker {
+    config: Config,
+    sink: Box<dyn DisplaySink>,
+    handle: Handle,
+    runtime: runtime::Handle,
+    detected_geometry: Arc<Mutex<Option<DetectedGeometry>>>,
+    telemetry: Arc<Mutex<Option<Telemetry>>>,
+    refresh_state: RefreshState,
+    stopped: Receiver<()>,
+}
+
+impl Worker {
+    fn run(mut self, jobs: Receiver<Job>) {
+        loop {
+            let job = match jobs.recv_timeout(keepalive_interval(&self.config)) {
+                Ok(job) => job,
+                Err(RecvTimeoutError::Timeout) => {
+                    self.sink.keep_alive();
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            match job {
+                Job::Reconfigure(config) => {
+                    if config.output != self.config.output
+                        || config.network != self.config.network
+                        || !config.kindle.same_session(&self.config.kindle)
+                    {
+                        // The new sink may not take the device over, so give it back first
+                        if let Err(err) = self.runtime.block_on(self.sink.release()) {
+                            warn!("failed to hand the display back: {}", err);
+                        }
+                        self.sink = sink::from_config(&config, &self.handle);
+                        // The new display has shown nothing yet and may have another geometry
+                        self.refresh_state = RefreshState::default();
+                        *self.detected_geometry.lock().unwrap() = None;
+                    } else if config.kindle != self.config.kindle {
+                        self.sink.update_kindle_config(&config.kindle);
+                    }
+                    self.config = *config;
+                }
+                Job::Update { now, frame } => {
+                    self.update_clock(&now, frame);
+                    debug!("done updating clock");
+                }
+                Job::Show { frame, done } => {
+                    let result = self.show(&frame);
+                    done.send(result).ok();
+                }
+            }
+        }
+        if let Err(err) = self.runtime.block_on(self.sink.release()) {
+            warn!("failed to hand the display back: {}", err);
+        }
+    }
+
+    fn show(&mut self, frame: &Frame) -> io::Result<()> {
+        self.runtime.block_on(self.sink.connect())?;
+        let waveform = self.config.display.profile().waveforms.graphics;
+        let result = self.sink.show_frame(frame, true, waveform);
+        self.sink.disconnect();
+        result
+    }
+
+    fn update_clock(&mut self, now: &DateTime<Local>, frame: Frame) {
+        let config = &self.config;
+        let sink = self.sink.as_mut();
+        let refresh_state = &mut self.refresh_state;
+        // Reduce update frequency at night time
+        if now.minute() % 5 != 0 && night_time(now) {
+            return;
+        }
+
+        let (_, art) = now.hour12();
+        let update = refresh_state.plan(&config.refresh, &frame, now, art);
+        if update == Update::Unchanged {
+            debug!("frame unchanged, nothing to send");
+            return;
+        }
+
+        if let Err(err) = self.runtime.block_on(sink.connect()) {
+            warn!("could not connect to the display: {}", err);
+            return;
+        }
+
+        // Only this thread stores it, so there's no need to hold the lock while the display is asked
+        let mut detected_geometry = *self.detected_geometry.lock().unwrap();
+        if detected_geometry.is_none() {
+            match sink.framebuffer_geometry() {
+                Ok(Some(detected)) => {
+                    let configured = config.display.profile();
+                    let (width, height) = detected.panel_size();
+                    if (width, height) != (configured.width, configured.height) {
+                        warn!(
+                            "configured display is {}x{} but the Kindle reports {}x{}, using the latter",
+                            configured.width, configured.height, width, height
+                        );
+                    } else {
+                        info!("Kindle framebuffer is {}x{}", width, height);
+                    }
+                    if detected.rotation != Orientation::Rotate0 {
+                        info!(
+                            "Kindle framebuffer is rotated by {} degrees, turning the clock that much less",
+                            detected.rotation.degrees()
+                        );
+                    }
+                    detected_geometry = Some(detected);
+                    *self.detected_geometry.lock().unwrap() = detected_geometry;
+                }
+                Ok(None) => debug!("display did not report its framebuffer geometry"),
+                Err(err) => warn!("failed to query Kindle framebuffer geometry: {}", err),
+            }
+        }
+
+        let waveforms = config
+            .display
+            .profile()
+            .with_detected(detected_geometry)
+            .waveforms;
+
+        let staged = sink.stage(&frame, &update.regions(frame.full_region()));
+        if let Err(err) = &staged {
+            warn!(
+                "failed to stage frame, sending it at the minute instead: {}",
+                err
+            );
+        }
+        if !wait_until(now, &self.stopped, sink, keepalive_interval(config)) {
+            debug!("shutting down, dropping the update");
+            return;
+        }
+        if staged.is_err() {
+            // A failed command closes the connection, so it has to be opened again
+            if let Err(err) = self.runtime.block_on(sink.connect()) {
+                warn!("could not connect to the display: {}", err);
+                return;
+            }
+        }
+        if let Err(err) = send_update(sink, &update, &frame, &waveforms) {
+            // What is on the display is anyone's guess now, so start over with a full refresh
+            error!("failed to update the display: {}", err);
+            *refresh_state = RefreshState::default();
+            sink.disconnect();
+            return;
+        }
+        info!(
+            "display updated {} ms after the minute",
+            (Local::now() - *now).num_milliseconds()
+        );
+        if config.refresh.verify {
+            if let Err(err) = verify_display(sink, &frame, &config.refresh, &waveforms) {
+                warn!("failed to verify the display: {}", err);
+            }
+        }
+        refresh_state.record(&update, frame, now, art);
+
+        if now.minute() == 0 && !night_time(&now) {
+            let (_, hour12) = now.hour12();
+            if let Err(err) = play_audio_for_hour(sink, now.hour(), hour12) {
+                warn!("failed to play hourly tune: {}", err);
+            }
+        }
+        // Connecting syncs the clock too, but a session can stay open for days while it drifts
+        if now.minute() == 0 && config.kindle.sync_clock {
+            sink.sync_clock();
+        }
+        match sink.telemetry() {
+            Ok(Some(telemetry)) => {
+                debug!("Kindle reports {}", telemetry);
+                let low_battery = config.kindle.low_battery;
+                let mut last = self.telemetry.lock().unwrap();
+                let was_fine =
+                    !matches!(&*last, Some(last) if last.battery_needs_attention(low_battery));
+                if telemetry.battery_needs_attention(low_battery) && was_fine {
+                    warn!("Kindle battery needs attention: {}", telemetry);
+                }
+                *last = Some(telemetry);
+            }
+            Ok(None) => {}
+            Err(err) => warn!("failed to query Kindle telemetry: {}", err),
+        }
+        sink.disconnect();
+    }
+}
+
+/// How long the worker may sit idle before the sink needs a keepalive
+fn keepalive_interval(config: &Config) -> Duration {
+    match config.kindle.keepalive_interval {
+        // A day of silence is as good as never
+        0 => Duration::from_secs(24 * 60 * 60),
+        seconds => Duration::from_secs(seconds.into()),
+    }
+}
+
+/// Waits for `deadline`, keeping the sink alive meanwhile. False if the device is being shut down
+fn wait_until(
+    deadline: &DateTime<Local>,
+    stopped: &Receiver<()>,
+    sink: &mut dyn DisplaySink,
+    keepalive_interval: Duration,
+) -> bool {
+    while let Ok(remaining) = (*deadline - Local::now()).to_std() {
+        let step = remaining.min(keepalive_interval);
+        match stopped.recv_timeout(step) {
+            Err(RecvTimeoutError::Timeout) if step < remaining => sink.keep_alive(),
+            Err(RecvTimeoutError::Timeout) => {}
+            _ => return false,
+        }
+    }
+    true
+}
+
+fn send_update(
+    sink: &mut dyn DisplaySink,
+    update: &Update,
+    frame: &Frame,
+    waveforms: &Waveforms,
+) -> std::io::Result<()> {
+    match update {
+        Update::Full { flash } => {
+            // A flash redraws everything, so it gets the waveform the graphics need
+            let content = if *flash {
+                Content::Graphics
+            } else {
+                frame.content_of(&frame.full_region())
+            };
+            sink.show_frame(frame, *flash, waveforms.for_content(content))?;
+        }
+        Update::Partial(regions) => {
+            debug!("sending {} changed regions: {:?}", regions.len(), regions);
+            for region in regions {
+                let waveform = waveforms.for_content(frame.content_of(region));
+                debug!("region {:?} uses waveform {:?}", region, waveform);
+                sink.show_region(frame, region, waveform)?;
+            }
+        }
+        Update::Cleanse => {
+            info!("cleansing display");
+            let black = Frame::solid(frame.width, frame.height, frame.gray_depth, 0);
+            sink.show_frame(&black, true, waveforms.graphics)?;
+            sink.clear()?;
+            sink.show_frame(frame, true, waveforms.graphics)?;
+        }
+        Update::Unchanged => {}
+    }
+    Ok(())
+}
+
+/// Compares what the display shows with the frame, sending it again if they differ too much
+fn verify_display(
+    sink: &mut dyn DisplaySink,
+    frame: &Frame,
+    config: &RefreshConfig,
+    waveforms: &Waveforms,
+) -> io::Result<()> {
+    let shown = match sink.read_back()? {
+        Some(shown) => shown,
+        None => {
+            debug!("display can't be read back");
+            return Ok(());
+        }
+    };
+    let mismatch = frame.mismatch(&shown);
+    if mismatch <= config.max_mismatch {
+        debug!("display shows the frame, mismatch {:.3}", mismatch);
+        return Ok(());
+    }
+    warn!(
+        "display doesn't show the frame that was sent, mismatch {:.3}",
+        mismatch
+    );
+    if let Some(path) = &config.mismatch_png {
+        fs::write(path, shown.to_png(&shown.full_region()))?;
+    }
+    if config.repush_on_mismatch {
+        info!("sending the frame again with a flash");
+        sink.show_frame(frame, true, waveforms.graphics)?;
+    }
+    Ok(())
+}
+
+fn night_time(now: &DateTime<Local>) -> bool {
+    now.hour() < 7 || now.hour() > 22
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::Region;
+    use crate::sink::{FramebufferSink, PngDirectorySink};
+
+    use futures::FutureExt;
+    use std::path::PathBuf;
+
+    const WIDTH: usize = 64;
+    const HEIGHT: usize = 48;
+
+    /// White, or white with a black block at x 20..30 and y 10..14
+    fn frame(with_block: bool) -> Frame {
+        let mut levels = vec![15; WIDTH * HEIGHT];
+        if with_block {
+            for y in 10..14 {
+                levels[y * WIDTH + 20..y * WIDTH + 30].fill(0);
+            }
+        }
+        Frame::new(WIDTH, HEIGHT, 4, levels)
+    }
+
+    fn scratch_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("eink-clock-{}-{}", name, std::process::id()));
+        fs::remove_file(&path).ok();
+        fs::remove_dir_all(&path).ok();
+        path
+    }
+
+    /// Plans, sends and records the frame the way the worker does
+    fn push(sink: &mut dyn DisplaySink, state: &mut RefreshState, frame: Frame) -> Update {
+        let config = Config::default();
+        let now = Local::now();
+        let update = state.plan(&config.refresh, &frame, &now, 1);
+        sink.connect().now_or_never().unwrap().unwrap();
+        send_update(sink, &update, &frame, &config.display.profile().waveforms).unwrap();
+        sink.disconnect();
+        state.record(&update, frame, &now, 1);
+        update
+    }
+
+    #[test]
+    fn framebuffer_gets_the_whole_frame_then_only_what_changed() {
+        let path = scratch_path("framebuffer");
+        let mut sink = FramebufferSink::new(path.clone());
+        let mut state = RefreshState::default();
+
+        assert_eq!(
+            push(&mut sink, &mut state, frame(false)),
+            Update::Full { flash: true }
+        );
+        assert_eq!(fs::read(&path).unwrap(), vec![255; WIDTH * HEIGHT]);
+
+        // Anything outside the changed region must be left alone
+        fs::write(&path, vec![1; WIDTH * HEIGHT]).unwrap();
+        let changed = Region {
+            x: 16,
+            y: 10,
+            width: 16,
+            height: 4,
+        };
+        assert_eq!(
+            push(&mut sink, &mut state, frame(true)),
+            Update::Partial(vec![changed])
+        );
+        let written = fs::read(&path).unwrap();
+        fs::remove_file(&path).ok();
+        for (i, &byte) in written.iter().enumerate() {
+            let (x, y) = (i % WIDTH, i / WIDTH);
+            let expected = match (x, y) {
+                (20..=29, 10..=13) => 0,
+                (16..=31, 10..=13) => 255,
+                _ => 1,
+            };
+            assert_eq!(byte, expected, "at ({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn png_directory_gets_the_whole_frame_then_only_what_changed() {
+        let directory = scratch_path("frames");
+        let mut sink = PngDirectorySink::new(directory.clone());
+        let mut state = RefreshState::default();
+        push(&mut sink, &mut state, frame(false));
+        push(&mut sink, &mut state, frame(true));
+
+        let mut names: Vec<String> = fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        fs::remove_dir_all(&directory).ok();
+        names.sort();
+        assert_eq!(names.len(), 2);
+        assert!(names[0].contains("-0001-full-flash-"), "{}", names[0]);
+        assert!(names[1].contains("-0002-region-16-10-"), "{}", names[1]);
+    }
+}
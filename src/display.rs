@@ -0,0 +1,204 @@
+use serde::{Deserialize, Serialize};
+
+use std::convert::TryFrom;
+
+use crate::waveform::{Waveform, Waveforms};
+
+/// Kindles and other panels with known geometry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Model {
+    KindleDx,
+    Kindle4,
+    Paperwhite1,
+    Paperwhite2,
+    Paperwhite3,
+    Paperwhite4,
+    Oasis,
+    Generic,
+}
+
+/// Clockwise rotation of the clock face relative to the framebuffer, in degrees.
+///
+/// 90 and 270 swap width and height, so on a panel that scans out in portrait they
+/// give a landscape clock while 0 and 180 give a portrait one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "u16", into = "u16")]
+pub enum Orientation {
+    Rotate0,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+impl Orientation {
+    pub fn degrees(self) -> u16 {
+        match self {
+            Orientation::Rotate0 => 0,
+            Orientation::Rotate90 => 90,
+            Orientation::Rotate180 => 180,
+            Orientation::Rotate270 => 270,
+        }
+    }
+
+    pub fn swaps_axes(self) -> bool {
+        matches!(self, Orientation::Rotate90 | Orientation::Rotate270)
+    }
+
+    /// From the quarter turns clockwise in a framebuffer's `rotate` attribute
+    pub fn from_quarter_turns(turns: usize) -> Self {
+        match turns % 4 {
+            0 => Orientation::Rotate0,
+            1 => Orientation::Rotate90,
+            2 => Orientation::Rotate180,
+            _ => Orientation::Rotate270,
+        }
+    }
+
+    /// What is left of this rotation once the framebuffer has been turned by `rotation`
+    pub fn less(self, rotation: Orientation) -> Self {
+        Self::from_quarter_turns(((self.degrees() + 360 - rotation.degrees()) / 90).into())
+    }
+}
+
+impl TryFrom<u16> for Orientation {
+    type Error = String;
+
+    fn try_from(degrees: u16) -> Result<Self, Self::Error> {
+        match degrees {
+            0 => Ok(Orientation::Rotate0),
+            90 => Ok(Orientation::Rotate90),
+            180 => Ok(Orientation::Rotate180),
+            270 => Ok(Orientation::Rotate270),
+            other => Err(format!(
+                "orientation must be 0, 90, 180 or 270 degrees, not {}",
+                other
+            )),
+        }
+    }
+}
+
+impl From<Orientation> for u16 {
+    fn from(orientation: Orientation) -> Self {
+        orientation.degrees()
+    }
+}
+
+/// Framebuffer geometry as reported by the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DetectedGeometry {
+    /// Visible width in pixels, as the driver currently lays the framebuffer out
+    pub width: usize,
+    /// Visible height in pixels, as the driver currently lays the framebuffer out
+    pub height: usize,
+    /// How far the driver already turns the framebuffer on the panel
+    pub rotation: Orientation,
+}
+
+impl DetectedGeometry {
+    /// Resolution of the panel itself, as the configured profiles give it
+    pub fn panel_size(&self) -> (usize, usize) {
+        if self.rotation.swaps_axes() {
+            (self.height, self.width)
+        } else {
+            (self.width, self.height)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisplayProfile {
+    /// Framebuffer width in pixels, before any rotation
+    pub width: usize,
+    /// Framebuffer height in pixels, before any rotation
+    pub height: usize,
+    pub dpi: f64,
+    pub orientation: Orientation,
+    /// Bits per pixel of grayscale the panel can reproduce
+    pub gray_depth: u8,
+    pub waveforms: Waveforms,
+}
+
+impl Model {
+    pub fn profile(self) -> DisplayProfile {
+        use Model::*;
+        let (width, height, dpi, gray_depth) = match self {
+            // E Ink Pearl 1200x824 150 DPI 4-bit 16-level grayscale
+            KindleDx => (824, 1200, 150.0, 4),
+            Kindle4 => (600, 800, 167.0, 4),
+            Paperwhite1 | Paperwhite2 => (758, 1024, 212.0, 4),
+            Paperwhite3 | Paperwhite4 => (1072, 1448, 300.0, 4),
+            Oasis => (1264, 1680, 300.0, 4),
+            Generic => (600, 800, 167.0, 8),
+        };
+        let waveforms = match self {
+            // eips on these predates waveform selection
+            KindleDx | Kindle4 | Generic => Waveforms {
+                digits: Waveform::Auto,
+                text: Waveform::Auto,
+                graphics: Waveform::Auto,
+            },
+            Paperwhite1 | Paperwhite2 | Paperwhite3 | Paperwhite4 | Oasis => Waveforms {
+                digits: Waveform::A2,
+                text: Waveform::Du,
+                graphics: Waveform::Gc16,
+            },
+        };
+        DisplayProfile {
+            width,
+            height,
+            dpi,
+            // All of these scan out in portrait, so turn them on their side for a landscape clock
+            orientation: Orientation::Rotate270,
+            gray_depth,
+            waveforms,
+        }
+    }
+}
+
+impl DisplayProfile {
+    /// Prefers the geometry reported by the device, if any, over the configured one. The
+    /// configured orientation is relative to the panel, so a framebuffer the driver has already
+    /// rotated needs that much less
+    pub fn with_detected(self, detected: Option<DetectedGeometry>) -> Self {
+        match detected {
+            Some(detected) => Self {
+                width: detected.width,
+                height: detected.height,
+                orientation: self.orientation.less(detected.rotation),
+                ..self
+            },
+            None => self,
+        }
+    }
+
+    /// Size of the clock face in the orientation it is read in
+    pub fn canvas(&self) -> Canvas {
+        let (width, height) = if self.orientation.swaps_axes() {
+            (self.height as f64, self.width as f64)
+        } else {
+            (self.width as f64, self.height as f64)
+        };
+        Canvas {
+            width,
+            height,
+            // The layout was designed on the Kindle DX, where the clock is 8 by 5.49 inches
+            unit: (width / 8.0).min(height / (824.0 / 150.0)),
+        }
+    }
+}
+
+/// Drawing area for the clock face, already in the orientation it is read in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Canvas {
+    pub width: f64,
+    pub height: f64,
+    /// Base size that fonts and strokes are scaled by, 150px on the Kindle DX
+    pub unit: f64,
+}
+
+impl Canvas {
+    pub fn is_portrait(&self) -> bool {
+        self.height > self.width
+    }
+}
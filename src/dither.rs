@@ -0,0 +1,142 @@
+use serde::{Deserialize, Serialize};
+
+/// How 8-bit gray is spread over the few levels an e-ink panel can show
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Dithering {
+    /// Round to the nearest level, gradients will band
+    None,
+    /// 4x4 Bayer matrix, stable from frame to frame so partial updates stay small
+    Ordered,
+    FloydSteinberg,
+    /// Diffuses only 3/4 of the error, keeping more contrast than Floyd–Steinberg
+    Atkinson,
+}
+
+const BAYER_4X4: [[i32; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// Maps each 8-bit gray pixel to one of `2^depth` levels, returning the level indices
+pub fn quantize(gray: &[u8], width: usize, depth: u8, dithering: Dithering) -> Vec<u8> {
+    let max_level = (1i32 << depth) - 1;
+    let to_level = |value: i32| ((value.clamp(0, 255) * max_level + 127) / 255) as u8;
+    let to_gray = |level: u8| level as i32 * 255 / max_level;
+
+    match dithering {
+        Dithering::None => gray.iter().map(|&value| to_level(value as i32)).collect(),
+        Dithering::Ordered => gray
+            .iter()
+            .enumerate()
+            .map(|(i, &value)| {
+                let threshold = BAYER_4X4[(i / width) % 4][(i % width) % 4];
+                // Offset by up to half a level either way
+                let offset = (threshold * 2 - 15) * 255 / (32 * max_level);
+                to_level(value as i32 + offset)
+            })
+            .collect(),
+        Dithering::FloydSteinberg => diffuse(
+            gray,
+            width,
+            &[(1, 0, 7), (-1, 1, 3), (0, 1, 5), (1, 1, 1)],
+            16,
+            to_level,
+            to_gray,
+        ),
+        Dithering::Atkinson => diffuse(
+            gray,
+            width,
+            &[
+                (1, 0, 1),
+                (2, 0, 1),
+                (-1, 1, 1),
+                (0, 1, 1),
+                (1, 1, 1),
+                (0, 2, 1),
+            ],
+            8,
+            to_level,
+            to_gray,
+        ),
+    }
+}
+
+/// Error diffusion with the given (dx, dy, weight) kernel, weights summing to at most `divisor`
+fn diffuse(
+    gray: &[u8],
+    width: usize,
+    kernel: &[(isize, usize, i32)],
+    divisor: i32,
+    to_level: impl Fn(i32) -> u8,
+    to_gray: impl Fn(u8) -> i32,
+) -> Vec<u8> {
+    let height = gray.len() / width;
+    let mut values: Vec<i32> = gray.iter().map(|&value| value as i32).collect();
+    let mut levels = Vec::with_capacity(gray.len());
+    for y in 0..height {
+        for x in 0..width {
+            let value = values[y * width + x];
+            let level = to_level(value);
+            levels.push(level);
+            let error = value - to_gray(level);
+            for &(dx, dy, weight) in kernel {
+                let (nx, ny) = (x as isize + dx, y + dy);
+                if nx >= 0 && (nx as usize) < width && ny < height {
+                    values[ny * width + nx as usize] += error * weight / divisor;
+                }
+            }
+        }
+    }
+    levels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [Dithering; 4] = [
+        Dithering::None,
+        Dithering::Ordered,
+        Dithering::FloydSteinberg,
+        Dithering::Atkinson,
+    ];
+
+    #[test]
+    fn rounds_to_the_nearest_level() {
+        let levels = quantize(&[0, 8, 9, 128, 255], 5, 4, Dithering::None);
+        assert_eq!(levels, vec![0, 0, 1, 8, 15]);
+    }
+
+    #[test]
+    fn black_and_white_stay_solid() {
+        for &dithering in &ALL {
+            assert_eq!(quantize(&[0; 64], 8, 4, dithering), vec![0; 64]);
+            assert_eq!(quantize(&[255; 64], 8, 4, dithering), vec![15; 64]);
+        }
+    }
+
+    #[test]
+    fn levels_stay_in_range() {
+        let gradient: Vec<u8> = (0..=255).collect();
+        for &dithering in &ALL {
+            for depth in 1..=4 {
+                let max_level = (1 << depth) - 1;
+                let levels = quantize(&gradient, 16, depth, dithering);
+                assert!(levels.iter().all(|&level| level <= max_level));
+            }
+        }
+    }
+
+    #[test]
+    fn ordered_dithering_turns_mid_gray_half_white() {
+        let levels = quantize(&[128; 16], 4, 1, Dithering::Ordered);
+        assert_eq!(levels.iter().filter(|&&level| level == 1).count(), 8);
+    }
+
+    #[test]
+    fn error_diffusion_keeps_the_average() {
+        for &dithering in &[Dithering::FloydSteinberg, Dithering::Atkinson] {
+            let levels = quantize(&[128; 256], 16, 1, dithering);
+            let white = levels.iter().filter(|&&level| level == 1).count();
+            assert!((100..=156).contains(&white), "{:?}: {}", dithering, white);
+        }
+    }
+}
@@ -0,0 +1,364 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use crate::waveform::Content;
+
+/// Dirty rows closer together than this are sent as one region
+const MERGE_GAP: usize = 16;
+/// More regions than this cost more in round trips than they save in pixels
+const MAX_REGIONS: usize = 4;
+/// Regions are widened to a multiple of this many pixels, which e-ink controllers prefer
+const ALIGNMENT: usize = 8;
+
+/// A quantized frame in framebuffer orientation, one gray level per pixel
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub width: usize,
+    pub height: usize,
+    pub gray_depth: u8,
+    levels: Vec<u8>,
+    /// What the renderer drew where, for choosing waveforms
+    zones: Vec<(Region, Content)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// What has to be sent to bring the display from the last frame to the next one
+#[derive(Debug, Clone, PartialEq)]
+pub enum Update {
+    Unchanged,
+    Partial(Vec<Region>),
+    /// Flashing clears ghosting at the cost of a black-white blink
+    Full {
+        flash: bool,
+    },
+    /// Cycle through solid black and white before the frame to clear stubborn ghosting
+    Cleanse,
+}
+
+impl Update {
+    /// Regions of the frame that will be drawn, for uploading them ahead of time
+    pub fn regions(&self, full: Region) -> Vec<Region> {
+        match self {
+            Update::Unchanged => vec![],
+            Update::Partial(regions) => regions.clone(),
+            Update::Full { .. } | Update::Cleanse => vec![full],
+        }
+    }
+}
+
+impl Region {
+    pub fn area(&self) -> usize {
+        self.width * self.height
+    }
+
+    fn bottom(&self) -> usize {
+        self.y + self.height
+    }
+
+    fn right(&self) -> usize {
+        self.x + self.width
+    }
+
+    fn intersects(&self, other: &Region) -> bool {
+        self.x < other.right()
+            && other.x < self.right()
+            && self.y < other.bottom()
+            && other.y < self.bottom()
+    }
+
+    fn union(&self, other: &Region) -> Region {
+        let (x, y) = (self.x.min(other.x), self.y.min(other.y));
+        Region {
+            x,
+            y,
+            width: self.right().max(other.right()) - x,
+            height: self.bottom().max(other.bottom()) - y,
+        }
+    }
+}
+
+impl Frame {
+    pub fn new(width: usize, height: usize, gray_depth: u8, levels: Vec<u8>) -> Self {
+        debug_assert_eq!(levels.len(), width * height);
+        Self {
+            width,
+            height,
+            gray_depth,
+            levels,
+            zones: vec![],
+        }
+    }
+
+    pub fn with_zones(self, zones: Vec<(Region, Content)>) -> Self {
+        Self { zones, ..self }
+    }
+
+    /// The most demanding content drawn in the region, graphics if nothing is known about it
+    pub fn content_of(&self, region: &Region) -> Content {
+        self.zones
+            .iter()
+            .filter(|(zone, _)| zone.intersects(region))
+            .map(|(_, content)| *content)
+            .max()
+            .unwrap_or(Content::Graphics)
+    }
+
+    /// Every pixel at the same level, 0 being black
+    pub fn solid(width: usize, height: usize, gray_depth: u8, level: u8) -> Self {
+        Self::new(width, height, gray_depth, vec![level; width * height])
+    }
+
+    pub fn full_region(&self) -> Region {
+        Region {
+            x: 0,
+            y: 0,
+            width: self.width,
+            height: self.height,
+        }
+    }
+
+    /// Share of pixels that differ from `previous`, between 0 and 1
+    pub fn changed_fraction(&self, previous: &Frame) -> f64 {
+        if self.levels.len() != previous.levels.len() {
+            return 1.;
+        }
+        let changed = self
+            .levels
+            .iter()
+            .zip(&previous.levels)
+            .filter(|(a, b)| a != b)
+            .count();
+        changed as f64 / self.levels.len() as f64
+    }
+
+    /// Bounding boxes of the pixels that differ from `previous`
+    pub fn dirty_regions(&self, previous: &Frame) -> Vec<Region> {
+        if (self.width, self.height, self.gray_depth)
+            != (previous.width, previous.height, previous.gray_depth)
+        {
+            return vec![self.full_region()];
+        }
+        let mut regions: Vec<Region> = vec![];
+        let rows = self
+            .levels
+            .chunks(self.width)
+            .zip(previous.levels.chunks(self.width));
+        for (y, (row, previous_row)) in rows.enumerate() {
+            let first = row.iter().zip(previous_row).position(|(a, b)| a != b);
+            let last = row.iter().zip(previous_row).rposition(|(a, b)| a != b);
+            if let (Some(first), Some(last)) = (first, last) {
+                let dirty_row = Region {
+                    x: first,
+                    y,
+                    width: last + 1 - first,
+                    height: 1,
+                };
+                match regions.last_mut() {
+                    Some(region) if y <= region.bottom() + MERGE_GAP => {
+                        *region = region.union(&dirty_row)
+                    }
+                    _ => regions.push(dirty_row),
+                }
+            }
+        }
+        while regions.len() > MAX_REGIONS {
+            // Merge the pair that is closest together vertically
+            let closest = (1..regions.len())
+                .min_by_key(|&i| regions[i].y - regions[i - 1].bottom())
+                .unwrap();
+            let merged = regions[closest - 1].union(&regions[closest]);
+            regions[closest - 1] = merged;
+            regions.remove(closest);
+        }
+        regions
+            .into_iter()
+            .map(|region| self.align(region))
+            .collect()
+    }
+
+    fn align(&self, region: Region) -> Region {
+        let x = region.x / ALIGNMENT * ALIGNMENT;
+        let padding = (ALIGNMENT - region.right() % ALIGNMENT) % ALIGNMENT;
+        let right = (region.right() + padding).min(self.width);
+        Region {
+            x,
+            width: right - x,
+            ..region
+        }
+    }
+
+    /// The region's levels stretched to 8-bit grays, row by row
+    pub fn to_gray(&self, region: &Region) -> Vec<u8> {
+        let max_level = (1u16 << self.gray_depth) - 1;
+        self.crop(region)
+            .into_iter()
+            .map(|level| (level as u16 * 255 / max_level) as u8)
+            .collect()
+    }
+
+    /// Share of pixels that look different from `shown` once both are 8-bit gray, between 0 and 1,
+    /// where a difference of less than half a gray level doesn't count
+    pub fn mismatch(&self, shown: &Frame) -> f64 {
+        if self.full_region() != shown.full_region() {
+            return 1.;
+        }
+        let levels = (1u16 << self.gray_depth.min(shown.gray_depth)) - 1;
+        let tolerance = (255 / levels / 2) as u8;
+        let expected = self.to_gray(&self.full_region());
+        let actual = shown.to_gray(&shown.full_region());
+        let mismatched = expected
+            .iter()
+            .zip(&actual)
+            .filter(|(a, b)| a.abs_diff(**b) > tolerance)
+            .count();
+        mismatched as f64 / expected.len().max(1) as f64
+    }
+
+    /// Identifies what the region of the frame looks like, without encoding it
+    pub fn fingerprint(&self, region: &Region) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        (region.x, region.y, region.width, region.height).hash(&mut hasher);
+        self.gray_depth.hash(&mut hasher);
+        self.crop(region).hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn crop(&self, region: &Region) -> Vec<u8> {
+        let mut cropped = Vec::with_capacity(region.area());
+        for row in self
+            .levels
+            .chunks(self.width)
+            .skip(region.y)
+            .take(region.height)
+        {
+            cropped.extend_from_slice(&row[region.x..region.right()]);
+        }
+        cropped
+    }
+
+    /// Encodes the region as a grayscale PNG with the frame's bit depth
+    pub fn to_png(&self, region: &Region) -> Vec<u8> {
+        let bit_depth = match self.gray_depth {
+            1 => png::BitDepth::One,
+            2 => png::BitDepth::Two,
+            4 => png::BitDepth::Four,
+            _ => png::BitDepth::Eight,
+        };
+        let cropped = self.crop(region);
+        let mut png_data = Vec::with_capacity(region.area());
+        {
+            let mut encoder =
+                png::Encoder::new(&mut png_data, region.width as u32, region.height as u32);
+            encoder.set_color(png::ColorType::Grayscale);
+            encoder.set_depth(bit_depth);
+            let mut writer = encoder.write_header().unwrap();
+            writer
+                .write_image_data(&pack_rows(&cropped, region.width, self.gray_depth))
+                .unwrap();
+        }
+        png_data
+    }
+}
+
+/// Packs levels into PNG scanlines: most significant bits first, each row padded to a whole byte
+fn pack_rows(levels: &[u8], width: usize, depth: u8) -> Vec<u8> {
+    if depth == 8 {
+        return levels.to_vec();
+    }
+    let depth = depth as usize;
+    let pixels_per_byte = 8 / depth;
+    let mut packed = Vec::with_capacity(levels.len() / pixels_per_byte + levels.len() / width);
+    for row in levels.chunks(width) {
+        for pixels in row.chunks(pixels_per_byte) {
+            let mut byte = 0u8;
+            for (i, level) in pixels.iter().enumerate() {
+                byte |= level << (8 - depth * (i + 1));
+            }
+            packed.push(byte);
+        }
+    }
+    packed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Not a multiple of [ALIGNMENT], so alignment has to stop at the edge
+    const WIDTH: usize = 60;
+    const HEIGHT: usize = 200;
+
+    /// A white frame with the given pixels black
+    fn frame_with(pixels: &[(usize, usize)]) -> Frame {
+        let mut levels = vec![15; WIDTH * HEIGHT];
+        for &(x, y) in pixels {
+            levels[y * WIDTH + x] = 0;
+        }
+        Frame::new(WIDTH, HEIGHT, 4, levels)
+    }
+
+    fn region(x: usize, y: usize, width: usize, height: usize) -> Region {
+        Region {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn unchanged_frame_has_no_dirty_regions() {
+        let frame = frame_with(&[(3, 3)]);
+        assert!(frame.dirty_regions(&frame.clone()).is_empty());
+    }
+
+    #[test]
+    fn dirty_rows_within_merge_gap_are_merged() {
+        let regions = frame_with(&[(10, 10), (13, 20)]).dirty_regions(&frame_with(&[]));
+        assert_eq!(regions, vec![region(8, 10, 8, 11)]);
+    }
+
+    #[test]
+    fn dirty_rows_further_apart_stay_separate() {
+        let regions = frame_with(&[(10, 10), (40, 100)]).dirty_regions(&frame_with(&[]));
+        assert_eq!(regions, vec![region(8, 10, 8, 1), region(40, 100, 8, 1)]);
+    }
+
+    #[test]
+    fn regions_are_aligned_without_passing_the_edge() {
+        let regions = frame_with(&[(57, 5)]).dirty_regions(&frame_with(&[]));
+        assert_eq!(regions, vec![region(56, 5, 4, 1)]);
+    }
+
+    #[test]
+    fn closest_regions_are_merged_down_to_the_limit() {
+        let pixels = [(0, 0), (0, 40), (0, 80), (0, 100), (0, 150)];
+        let regions = frame_with(&pixels).dirty_regions(&frame_with(&[]));
+        assert_eq!(regions.len(), MAX_REGIONS);
+        assert_eq!(
+            regions,
+            vec![
+                region(0, 0, 8, 1),
+                region(0, 40, 8, 1),
+                region(0, 80, 8, 21),
+                region(0, 150, 8, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn frames_of_another_depth_are_dirty_all_over() {
+        let frame = Frame::new(WIDTH, HEIGHT, 8, vec![255; WIDTH * HEIGHT]);
+        assert_eq!(
+            frame.dirty_regions(&frame_with(&[])),
+            vec![frame.full_region()]
+        );
+    }
+}
@@ -0,0 +1,518 @@
+use chrono::prelude::*;
+
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+
+use crate::command::{self, Command, CommandError, Output};
+use crate::config::{Framework, KindleConfig};
+use crate::display::{DetectedGeometry, Orientation};
+use crate::frame::{Frame, Region};
+use crate::telemetry::Telemetry;
+use crate::waveform::Waveform;
+
+/// Runs commands on the Kindle, whether over SSH or on the device itself
+pub trait Shell {
+    /// Writes `data` to a file on the Kindle
+    fn upload(&mut self, path: &Path, data: &[u8]) -> Result<(), CommandError>;
+
+    /// Runs the command and collects what it returned, whatever its exit status
+    fn run(&mut self, command: &Command) -> Result<Output, CommandError>;
+
+    /// Runs the command, failing unless it exits successfully
+    fn exec(&mut self, command: &Command) -> Result<Output, CommandError> {
+        let output = self.run(command)?;
+        if !output.success() {
+            return Err(CommandError::Failed {
+                command: command.to_shell(),
+                status: output.status,
+                stderr: output.stderr,
+            });
+        }
+        Ok(output)
+    }
+}
+
+/// Program that draws on the screen, `eips` on Kindles and FBInk wherever it is installed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DrawingTool {
+    Eips,
+    /// Path FBInk was found at
+    Fbink(String),
+}
+
+/// Where FBInk gets installed off `$PATH`
+const FBINK_PATHS: &[&str] = &[
+    // Kindle, with the libkh package from the jailbreak
+    "/mnt/us/libkh/bin/fbink",
+    // Kobo, with KFMon, which NickelMenu setups also use
+    "/usr/local/kfmon/bin/fbink",
+    // Kobo, bundled with KOReader
+    "/mnt/onboard/.adds/koreader/fbink",
+];
+
+/// Prefers FBInk, which works on firmwares where `eips` is limited or missing
+pub fn probe_drawing_tool(shell: &mut impl Shell) -> Result<DrawingTool, CommandError> {
+    // Fails when FBInk is nowhere to be found, which only means using eips
+    let fbink = shell
+        .run(&command::sh(&format!(
+            "command -v fbink || for path in {}; do [ -x \"$path\" ] && echo \"$path\" && break; done",
+            FBINK_PATHS.join(" ")
+        )))?
+        .text();
+    Ok(match fbink.lines().next().map(str::trim) {
+        Some(path) if !path.is_empty() => DrawingTool::Fbink(path.to_owned()),
+        _ => DrawingTool::Eips,
+    })
+}
+
+/// Staged images kept around in case the same picture comes up again
+const MAX_CACHED_IMAGES: usize = 32;
+
+/// Files uploaded to the Kindle under the MD5 of their contents, so each is only sent once
+#[derive(Debug)]
+pub struct AssetCache {
+    directory: PathBuf,
+    /// Names of the files whose checksum matched their name, least recently used first,
+    /// once the Kindle has been asked
+    files: Option<VecDeque<String>>,
+}
+
+impl AssetCache {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+            files: None,
+        }
+    }
+
+    fn files(&mut self, shell: &mut impl Shell) -> Result<&mut VecDeque<String>, CommandError> {
+        if self.files.is_none() {
+            // md5sum fails on an empty directory, which just means nothing is cached yet
+            let listing = shell
+                .run(&command::md5sum_directory(&self.directory))?
+                .text();
+            let files: VecDeque<String> = parse_md5sum(&listing).collect();
+            debug!("{} files cached on the Kindle", files.len());
+            self.files = Some(files);
+        }
+        Ok(self.files.get_or_insert_with(VecDeque::new))
+    }
+
+    /// Path of a copy of `data` on the Kindle, uploading it unless it is already there
+    pub fn ensure(
+        &mut self,
+        shell: &mut impl Shell,
+        data: &[u8],
+        extension: &str,
+    ) -> Result<PathBuf, CommandError> {
+        let name = format!("{:x}.{}", md5::compute(data), extension);
+        let path = self.directory.join(&name);
+        let files = self.files(shell)?;
+        if let Some(index) = files.iter().position(|file| *file == name) {
+            files.remove(index);
+        } else {
+            shell.upload(&path, data)?;
+        }
+        // Either way it was just used
+        files.push_back(name);
+        Ok(path)
+    }
+
+    /// Deletes the least recently used files with the extension beyond the newest `keep`
+    pub fn prune(
+        &mut self,
+        shell: &mut impl Shell,
+        extension: &str,
+        keep: usize,
+    ) -> Result<(), CommandError> {
+        let suffix = format!(".{}", extension);
+        let files = self.files(shell)?;
+        let matching: Vec<String> = files
+            .iter()
+            .filter(|file| file.ends_with(&suffix))
+            .cloned()
+            .collect();
+        if matching.len() <= keep {
+            return Ok(());
+        }
+        let stale = &matching[..matching.len() - keep];
+        files.retain(|file| !stale.contains(file));
+        let mut rm = Command::new("rm").arg("-f");
+        for file in stale {
+            rm = rm.arg(self.directory.join(file).to_string_lossy());
+        }
+        shell.exec(&rm).map(|_| ())
+    }
+
+    /// Lists the cache again before next using it, e.g. when the Kindle may have rebooted
+    pub fn forget(&mut self) {
+        self.files = None;
+    }
+}
+
+/// Names of the files in `md5sum` output whose contents match the checksum in their name
+fn parse_md5sum(listing: &str) -> impl Iterator<Item = String> + '_ {
+    listing.lines().filter_map(|line| {
+        let mut fields = line.split_whitespace();
+        let (checksum, name) = (fields.next()?, fields.next()?);
+        if name.split('.').next() == Some(checksum) {
+            Some(name.to_owned())
+        } else {
+            None
+        }
+    })
+}
+
+/// Draws with the device's [DrawingTool], probing for it on first use
+#[derive(Debug, Default)]
+pub struct Screen {
+    tool: Option<DrawingTool>,
+    /// Where the images uploaded by [Screen::stage] went, by fingerprint
+    staged: HashMap<u64, PathBuf>,
+}
+
+impl Screen {
+    fn tool(&mut self, shell: &mut impl Shell) -> Result<DrawingTool, CommandError> {
+        if let Some(tool) = &self.tool {
+            return Ok(tool.clone());
+        }
+        let tool = probe_drawing_tool(shell)?;
+        info!("drawing with {:?}", tool);
+        self.tool = Some(tool.clone());
+        Ok(tool)
+    }
+
+    /// Uploads the regions of the frame ahead of time, so drawing them only takes a command
+    pub fn stage(
+        &mut self,
+        shell: &mut impl Shell,
+        assets: &mut AssetCache,
+        frame: &Frame,
+        regions: &[Region],
+    ) -> Result<(), CommandError> {
+        // Whatever was staged last time has been drawn or is no longer wanted
+        self.staged.clear();
+        for region in regions {
+            let path = assets.ensure(shell, &frame.to_png(region), "png")?;
+            self.staged.insert(frame.fingerprint(region), path);
+        }
+        assets.prune(shell, "png", MAX_CACHED_IMAGES)
+    }
+
+    /// Forgets about staged images, e.g. when the Kindle may have rebooted and lost them
+    pub fn forget_staged(&mut self) {
+        self.staged.clear();
+    }
+
+    /// Path of the region of the frame on the Kindle, uploading it to `path` unless it was staged
+    fn image_path(
+        &self,
+        shell: &mut impl Shell,
+        frame: &Frame,
+        region: &Region,
+        path: &Path,
+    ) -> Result<PathBuf, CommandError> {
+        if let Some(staged) = self.staged.get(&frame.fingerprint(region)) {
+            return Ok(staged.clone());
+        }
+        shell.upload(path, &frame.to_png(region))?;
+        Ok(path.to_owned())
+    }
+
+    pub fn show_image(
+        &mut self,
+        shell: &mut impl Shell,
+        frame: &Frame,
+        full_update: bool,
+        waveform: Waveform,
+    ) -> Result<(), CommandError> {
+        let path = self.image_path(
+            shell,
+            frame,
+            &frame.full_region(),
+            Path::new("/dev/shm/out.png"),
+        )?;
+        let cmd = match self.tool(shell)? {
+            DrawingTool::Eips => command::eips_image(&path, full_update, None, waveform),
+            DrawingTool::Fbink(fbink) => {
+                command::fbink_image(&fbink, &path, full_update, None, waveform)
+            }
+        };
+        shell.exec(&cmd).map(|_| ())
+    }
+
+    /// Draws a region of the frame in place, without flashing
+    pub fn show_region(
+        &mut self,
+        shell: &mut impl Shell,
+        frame: &Frame,
+        region: &Region,
+        waveform: Waveform,
+    ) -> Result<(), CommandError> {
+        let path = self.image_path(shell, frame, region, Path::new("/dev/shm/region.png"))?;
+        let position = Some((region.x, region.y));
+        let cmd = match self.tool(shell)? {
+            DrawingTool::Eips => command::eips_image(&path, false, position, waveform),
+            DrawingTool::Fbink(fbink) => {
+                command::fbink_image(&fbink, &path, false, position, waveform)
+            }
+        };
+        shell.exec(&cmd).map(|_| ())
+    }
+
+    /// Blanks the screen to white with a flash
+    pub fn clear(&mut self, shell: &mut impl Shell) -> Result<(), CommandError> {
+        let cmd = match self.tool(shell)? {
+            DrawingTool::Eips => command::eips_clear(),
+            DrawingTool::Fbink(fbink) => command::fbink_clear(&fbink),
+        };
+        shell.exec(&cmd).map(|_| ())
+    }
+}
+
+/// The Java process behind the Kindle's user interface
+const FRAMEWORK_PROCESS: &str = "cvm";
+
+/// Keeps the framework and screensaver from drawing over the clock, as configured
+pub fn take_over(shell: &mut impl Shell, config: &KindleConfig) -> Result<(), CommandError> {
+    match config.framework {
+        Framework::Leave => {}
+        Framework::Suspend => run_leniently(shell, &command::killall("STOP", FRAMEWORK_PROCESS))?,
+        Framework::Stop => run_leniently(shell, &command::framework_stop())?,
+    }
+    if config.prevent_screensaver {
+        shell.exec(&command::lipc_set_prop(
+            "com.lab126.powerd",
+            "preventScreenSaver",
+            "1",
+        ))?;
+    }
+    Ok(())
+}
+
+/// Undoes [take_over]
+pub fn hand_back(shell: &mut impl Shell, config: &KindleConfig) -> Result<(), CommandError> {
+    if config.prevent_screensaver {
+        shell.exec(&command::lipc_set_prop(
+            "com.lab126.powerd",
+            "preventScreenSaver",
+            "0",
+        ))?;
+    }
+    match config.framework {
+        Framework::Leave => {}
+        Framework::Suspend => run_leniently(shell, &command::killall("CONT", FRAMEWORK_PROCESS))?,
+        Framework::Stop => run_leniently(shell, &command::framework_start())?,
+    }
+    Ok(())
+}
+
+/// For commands that fail when there is nothing to do, such as stopping a stopped framework
+fn run_leniently(shell: &mut impl Shell, command: &Command) -> Result<(), CommandError> {
+    let output = shell.run(command)?;
+    if !output.success() {
+        debug!(
+            "`{}` exited with status {}: {}",
+            command,
+            output.status,
+            output.stderr.trim()
+        );
+    }
+    Ok(())
+}
+
+/// How many seconds the Kindle's clock is ahead of this machine's, setting it from this machine's
+/// when that is more than `max_drift`
+pub fn sync_clock(shell: &mut impl Shell, max_drift: u32) -> Result<i64, CommandError> {
+    let before = Utc::now();
+    let output = shell.exec(&command::date_epoch())?.text();
+    let after = Utc::now();
+    let kindle_time = output.trim().parse::<i64>().map_err(|_| {
+        CommandError::Unexpected(format!("unexpected date output {:?}", output.trim()))
+    })?;
+    // The Kindle read its clock somewhere during the round trip
+    let host_time = before + (after - before) / 2;
+    let offset = kindle_time - host_time.timestamp();
+    if offset.unsigned_abs() > max_drift as u64 {
+        shell.exec(&command::date_set(Utc::now()))?;
+        run_leniently(shell, &command::hwclock_save())?;
+    }
+    Ok(offset)
+}
+
+pub fn amixer_set_master_volume(shell: &mut impl Shell, volume: u8) -> Result<(), CommandError> {
+    shell
+        .exec(&command::amixer_set_master_volume(volume))
+        .map(|_| ())
+}
+
+pub fn aplay_audio_nonblocking(
+    shell: &mut impl Shell,
+    assets: &mut AssetCache,
+    audio: &[u8],
+) -> Result<(), CommandError> {
+    let path = assets.ensure(shell, audio, "wav")?;
+    shell.exec(&command::aplay_nonblocking(&path)).map(|_| ())
+}
+
+/// Where the kernel describes each framebuffer, in a directory named after it
+pub const SYSFS_GRAPHICS: &str = "/sys/class/graphics";
+
+/// The framebuffer eips and FBInk draw to
+const KINDLE_FRAMEBUFFER: &str = "fb0";
+
+/// Visible resolution of the Kindle's framebuffer and how far its driver rotates it
+pub fn query_framebuffer_geometry(
+    shell: &mut impl Shell,
+) -> Result<Option<DetectedGeometry>, CommandError> {
+    let (width, height) = match query_framebuffer_size(shell)? {
+        Some(size) => size,
+        None => return Ok(None),
+    };
+    // Kernels without the attribute can't rotate the framebuffer
+    let rotation = framebuffer_attribute(shell, KINDLE_FRAMEBUFFER, "rotate")?
+        .map_or(Orientation::Rotate0, Orientation::from_quarter_turns);
+    Ok(Some(DetectedGeometry {
+        width,
+        height,
+        rotation,
+    }))
+}
+
+/// Visible resolution of the Kindle's framebuffer as (width, height), if it could be determined
+fn query_framebuffer_size(shell: &mut impl Shell) -> Result<Option<(usize, usize)>, CommandError> {
+    // Devices without eips fall back to sysfs, so its exit status doesn't matter
+    let eips_info = shell.run(&command::eips_info())?.text();
+    if let Some(size) = parse_eips_info(&eips_info) {
+        return Ok(Some(size));
+    }
+    // Not virtual_size, which includes the area the driver pans over
+    let modes = shell
+        .exec(&command::cat(
+            &Path::new(SYSFS_GRAPHICS)
+                .join(KINDLE_FRAMEBUFFER)
+                .join("modes"),
+        ))?
+        .text();
+    Ok(parse_framebuffer_mode(&modes))
+}
+
+/// What is in the framebuffer right now, as an 8-bit gray frame
+pub fn read_framebuffer(shell: &mut impl Shell) -> Result<Option<Frame>, CommandError> {
+    let (width, height) = match query_framebuffer_size(shell)? {
+        Some(size) => size,
+        None => return Ok(None),
+    };
+    let bits_per_pixel =
+        framebuffer_attribute(shell, KINDLE_FRAMEBUFFER, "bits_per_pixel")?.unwrap_or(8);
+    // Rows can be padded, older kernels don't say so and aren't
+    let stride = framebuffer_attribute(shell, KINDLE_FRAMEBUFFER, "stride")?
+        .unwrap_or(width * bits_per_pixel / 8);
+    let raw = shell
+        .exec(&command::dd(Path::new("/dev/fb0"), stride, height))?
+        .stdout;
+    let mut levels = Vec::with_capacity(width * height);
+    for row in raw.chunks(stride).take(height) {
+        match bits_per_pixel {
+            8 => levels.extend(row.iter().take(width)),
+            // As on the Kindle 3 and DX, two pixels per byte and 0 is white
+            4 => levels.extend(
+                row.iter()
+                    .flat_map(|byte| [byte >> 4, byte & 0xf])
+                    .take(width)
+                    .map(|nibble| 255 - nibble * 17),
+            ),
+            _ => return Ok(None),
+        }
+    }
+    if levels.len() != width * height {
+        return Err(CommandError::Unexpected(
+            "framebuffer was shorter than its size".to_owned(),
+        ));
+    }
+    Ok(Some(Frame::new(width, height, 8, levels)))
+}
+
+pub fn query_telemetry(shell: &mut impl Shell) -> Result<Telemetry, CommandError> {
+    let report = shell.exec(&command::telemetry_report())?.text();
+    Ok(Telemetry::parse(&report))
+}
+
+/// A number from the sysfs directory of a framebuffer such as `fb0`, if the kernel has it
+fn framebuffer_attribute(
+    shell: &mut impl Shell,
+    device: &str,
+    name: &str,
+) -> Result<Option<usize>, CommandError> {
+    let path = Path::new(SYSFS_GRAPHICS).join(device).join(name);
+    let output = shell.run(&command::cat(&path))?;
+    Ok(output.text().trim().parse().ok())
+}
+
+/// Picks the resolution out of the first of the framebuffer's video modes, such as
+/// `U:758x1024p-0`
+pub fn parse_framebuffer_mode(modes: &str) -> Option<(usize, usize)> {
+    let mode = modes.lines().next()?;
+    let resolution = mode
+        .split_once(':')
+        .map_or(mode, |(_, resolution)| resolution);
+    let (width, rest) = resolution.split_once('x')?;
+    let height_digits = rest
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(rest.len());
+    Some((width.parse().ok()?, rest[..height_digits].parse().ok()?))
+}
+
+/// Picks `xres` and `yres` out of the variable framebuffer info printed by `eips -i`
+fn parse_eips_info(info: &str) -> Option<(usize, usize)> {
+    let mut tokens = info.split_whitespace();
+    let (mut xres, mut yres) = (None, None);
+    while let Some(token) = tokens.next() {
+        match token {
+            "xres:" => xres = tokens.next().and_then(|value| value.parse().ok()),
+            "yres:" => yres = tokens.next().and_then(|value| value.parse().ok()),
+            _ => {}
+        }
+    }
+    xres.zip(yres)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn md5sum_listing_keeps_files_named_after_their_checksum() {
+        let listing = concat!(
+            "d41d8cd98f00b204e9800998ecf8427e  d41d8cd98f00b204e9800998ecf8427e.png\n",
+            "0123456789abcdef0123456789abcdef  chime.wav\n",
+            "md5sum: can't open '*': No such file or directory\n",
+        );
+        assert_eq!(
+            parse_md5sum(listing).collect::<Vec<_>>(),
+            vec!["d41d8cd98f00b204e9800998ecf8427e.png"]
+        );
+    }
+
+    #[test]
+    fn eips_info_gives_the_visible_resolution() {
+        let info = concat!(
+            "Variable framebuffer info\n",
+            "    xres:                  758    yres:                 1024\n",
+            "    xres_virtual:          768    yres_virtual:         2048\n",
+            "    bits_per_pixel:          8    grayscale:               1\n",
+        );
+        assert_eq!(parse_eips_info(info), Some((758, 1024)));
+        assert_eq!(parse_eips_info("sh: eips: not found"), None);
+    }
+
+    #[test]
+    fn framebuffer_mode_gives_the_resolution_of_the_first() {
+        assert_eq!(parse_framebuffer_mode("U:758x1024p-0\n"), Some((758, 1024)));
+        assert_eq!(
+            parse_framebuffer_mode("S:1200x824p-60\nU:824x1200p-60\n"),
+            Some((1200, 824))
+        );
+        assert_eq!(parse_framebuffer_mode(""), None);
+    }
+}
@@ -0,0 +1,71 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::process::Stdio;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::command::{Command, CommandError, Output};
+use crate::kindle::Shell;
+
+/// Runs commands directly, for when eink-clock runs on the Kindle itself
+pub struct LocalShell {
+    /// How long a command may run before it is killed
+    pub timeout: Duration,
+}
+
+impl Shell for LocalShell {
+    fn upload(&mut self, path: &Path, data: &[u8]) -> Result<(), CommandError> {
+        debug!("write {}", path.display());
+        Ok(fs::write(path, data)?)
+    }
+
+    fn run(&mut self, command: &Command) -> Result<Output, CommandError> {
+        let cmd = command.to_shell();
+        debug!("{}", cmd);
+        let mut child = std::process::Command::new("/bin/sh")
+            .arg("-c")
+            .arg(&cmd)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        // Read both pipes while waiting, a framebuffer dump would fill them up otherwise
+        let stdout = child.stdout.take().map(read_in_background);
+        let stderr = child.stderr.take().map(read_in_background);
+        let deadline = Instant::now() + self.timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() > deadline {
+                child.kill()?;
+                child.wait()?;
+                return Err(CommandError::Io(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("`{}` took longer than {:?}", cmd, self.timeout),
+                )));
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        Ok(Output {
+            status: status.code().unwrap_or(-1),
+            stdout: collect(stdout)?,
+            stderr: String::from_utf8_lossy(&collect(stderr)?).into_owned(),
+        })
+    }
+}
+
+fn read_in_background(mut pipe: impl Read + Send + 'static) -> JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut data = Vec::new();
+        pipe.read_to_end(&mut data)?;
+        Ok(data)
+    })
+}
+
+fn collect(reader: Option<JoinHandle<io::Result<Vec<u8>>>>) -> io::Result<Vec<u8>> {
+    match reader {
+        Some(reader) => reader.join().expect("pipe reader panicked"),
+        None => Ok(vec![]),
+    }
+}
@@ -6,50 +6,54 @@ extern crate log;
 
 mod art;
 mod audio;
+mod calibration;
 mod clock;
+mod command;
+mod config;
+mod device;
+mod display;
+mod dither;
+mod frame;
+mod kindle;
+mod local;
 mod network;
+mod refresh;
 mod render;
+mod secrets;
+mod sink;
 mod ssh;
+mod telemetry;
+mod test_pattern;
 mod usb;
+mod waveform;
 mod weather;
 
-use audio::*;
 use clock::*;
+use config::*;
+use device::Device;
+use display::{Canvas, DisplayProfile};
+use frame::Frame;
 use render::*;
-use ssh::*;
+use telemetry::Telemetry;
+use test_pattern::*;
+use waveform::Zone;
 use weather::*;
 
 use std::env;
 use std::io::Write;
-use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
 
+use cairo::Context;
 use chrono::prelude::*;
 use chrono::{Duration, DurationRound};
+use clap::SubCommand;
 use metar::Metar;
-use rtnetlink::{new_connection, Handle};
+use rtnetlink::new_connection;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::time;
 
-/// E Ink Pearl 1200x824 150 DPI 4-bit 16-level grayscale
-const WIDTH: usize = 1200;
-const HEIGHT: usize = 824;
-const DPI: f64 = 150.0;
-const FONT: &str = "Inter";
-const EMOJI_FONT: &str = "OpenMoji";
-
-const WEATHER_STATION: &str = "KTPA";
-const TEMPERATURE_UNITS: uom::si::thermodynamic_temperature::degree_fahrenheit =
-    uom::si::thermodynamic_temperature::degree_fahrenheit;
-const WIND_SPEED_UNITS: uom::si::velocity::mile_per_hour = uom::si::velocity::mile_per_hour;
-
-const PI_IP_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 2, 1));
-const KINDLE_IP_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 2, 2));
-const KINDLE_SSH_PORT: u16 = 22;
-const KINDLE_USERNAME: &str = "root";
-const KINDLE_PASSWORD: &str = "root";
-const KINDLE_CONNECT_TIMEOUT: u64 = 1000;
-const KINDLE_INTERFACE: &str = "usb0";
-const KINDLE_VENDOR_ID: u16 = 0x0525;
-const KINDLE_PRODUCT_ID: u16 = 0xa4a2;
+/// How often `--watch-config` looks at the file's modification time
+const CONFIG_WATCH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
 
 #[tokio::main]
 pub async fn main() {
@@ -62,26 +66,63 @@ pub async fn main() {
         (author: crate_authors!())
         (about: crate_description!())
         (@arg debug: --debug "To debug locally, eink-clock will simply output the PNG for the current time")
+        (@arg config: -c --config +takes_value "Path to the TOML configuration file, defaults to eink-clock.toml in the working directory")
+        (@arg watch_config: --("watch-config") "Reload the configuration whenever the file changes, in addition to on SIGHUP")
+    )
+    .subcommand(
+        SubCommand::with_name("test-pattern")
+            .about("Shows a gray step wedge, gradient and text ladder on the Kindle for tuning the calibration table"),
     )
     .get_matches();
+    let mut config_source = ConfigSource::new(
+        Path::new(matches.value_of("config").unwrap_or(DEFAULT_CONFIG_PATH)),
+        matches.is_present("config"),
+    );
+    let mut config = match config_source.load() {
+        Ok(config) => config,
+        Err(err) => {
+            error!("{}: {}", config_source.path().display(), err);
+            std::process::exit(1);
+        }
+    };
+    let watch_config = matches.is_present("watch_config");
+    log_display_profile(&config);
     let debug = matches.is_present("debug");
+    if matches.subcommand_matches("test-pattern").is_some() {
+        show_test_pattern(&config, debug).await;
+        return;
+    }
     if debug {
         info!("In debug mode, printing png to stdout");
-        let metar_string = weather::get_current_metar_data().await;
+        let metar_string = weather::get_current_metar_data(&config.weather).await;
         let metar = metar_string
             .as_ref()
             .ok()
             .and_then(|metar_str| parse_metar_data(metar_str).ok());
         let next_minute = start_of_next_minute(Local::now());
-        let png = generate_image(metar.as_ref(), &next_minute).await;
-        std::io::stdout().write_all(&png).unwrap();
+        let frame = generate_image(
+            &config,
+            &config.display.profile(),
+            metar.as_ref(),
+            None,
+            &next_minute,
+        )
+        .await;
+        std::io::stdout()
+            .write_all(&frame.to_png(&frame.full_region()))
+            .unwrap();
         return;
     }
     let (connection, handle, _) = new_connection().unwrap();
     tokio::spawn(connection);
+    let mut hangup = signal(SignalKind::hangup()).expect("failed to listen for SIGHUP");
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+    let mut interrupt = signal(SignalKind::interrupt()).expect("failed to listen for SIGINT");
+    let device = Device::spawn(&config, handle);
 
     let mut metar_state = None;
-    loop {
+    let mut config_watch = time::interval(CONFIG_WATCH_INTERVAL);
+    'clock: loop {
         let now = Local::now();
         let next_minute = start_of_next_minute(now);
         let should_update_metar = match &metar_state {
@@ -89,7 +130,7 @@ pub async fn main() {
             None => true,
         };
         if should_update_metar {
-            let new_metar_string = weather::get_current_metar_data().await;
+            let new_metar_string = weather::get_current_metar_data(&config.weather).await;
             if let Ok(new_metar_string) = new_metar_string {
                 let new_metar_is_ok = parse_metar_data(&new_metar_string)
                     .map_err(|err| error!("could not parse metar: {}", err))
@@ -103,97 +144,154 @@ pub async fn main() {
             .as_ref()
             .and_then(|(metar_str, _)| parse_metar_data(metar_str).ok());
 
-        let png = generate_image(metar.as_ref(), &next_minute).await;
-        // ^ precompute
-        match (next_minute - Local::now()).to_std() {
-            Ok(duration) => time::sleep(duration).await,
-            Err(_) => {}
+        let profile = config
+            .display
+            .profile()
+            .with_detected(device.detected_geometry());
+        let telemetry = device.telemetry();
+        let frame = generate_image(
+            &config,
+            &profile,
+            metar.as_ref(),
+            telemetry.as_ref(),
+            &next_minute,
+        )
+        .await;
+        // The device uploads the frame now and only draws it at the minute
+        device.update(next_minute, frame);
+        // A reload takes effect from the next frame, the queued one is still shown at its minute
+        while let Ok(duration) = (next_minute - Local::now()).to_std() {
+            let reload = tokio::select! {
+                _ = time::sleep(duration) => break,
+                _ = hangup.recv() => {
+                    info!("received SIGHUP, reloading config");
+                    true
+                }
+                _ = config_watch.tick(), if watch_config => config_source.has_changed(),
+                _ = terminate.recv() => {
+                    info!("received SIGTERM, shutting down");
+                    break 'clock;
+                }
+                _ = interrupt.recv() => {
+                    info!("received SIGINT, shutting down");
+                    break 'clock;
+                }
+            };
+            if reload {
+                let station = config.weather.station.clone();
+                if reload_config(&mut config_source, &mut config) {
+                    device.reconfigure(&config);
+                    if config.weather.station != station {
+                        metar_state = None;
+                    }
+                }
+            }
         }
         debug!("timer went off");
-        update_clock(&handle, &next_minute, &png).await;
-        debug!("done updating clock")
     }
+    device.finish();
 }
 
-fn start_of_next_minute<Tz: TimeZone>(now: DateTime<Tz>) -> DateTime<Tz> {
-    let one_minute = Duration::minutes(1);
-    now.duration_trunc(one_minute).unwrap() + one_minute
-}
-
-async fn update_clock(handle: &Handle, now: &DateTime<Local>, png: &Vec<u8>) {
-    // Reduce update frequency at night time
-    if now.minute() % 5 != 0 && night_time(now) {
+async fn show_test_pattern(config: &Config, debug: bool) {
+    let profile = config.display.profile();
+    let frame = render_frame(config, &profile, |ctx, canvas| {
+        draw_test_pattern(ctx, canvas, config);
+        vec![]
+    });
+    if debug {
+        info!("In debug mode, printing test pattern png to stdout");
+        std::io::stdout()
+            .write_all(&frame.to_png(&frame.full_region()))
+            .unwrap();
         return;
     }
+    let (connection, handle, _) = new_connection().unwrap();
+    tokio::spawn(connection);
+    let device = Device::spawn(config, handle);
+    let shown = device.show(frame);
+    device.finish();
+    if let Err(err) = shown {
+        error!("could not show the test pattern: {}", err);
+        std::process::exit(1);
+    }
+    info!("test pattern shown, adjust display.calibration and run again to compare");
+}
 
-    network::setup_if_down(handle)
-        .await
-        .expect("failed to set up network via rtnetlink");
-
-    let ssh_tcp_stream = match open_tcp_connection() {
-        Ok(ssh_tcp_stream) => ssh_tcp_stream,
+/// Swaps in the config from disk, keeping the current one if the new one is invalid. Returns
+/// whether the config changed.
+fn reload_config(source: &mut ConfigSource, config: &mut Config) -> bool {
+    match source.load() {
+        Ok(new_config) => {
+            // Compared directly as well, since the diff doesn't show secrets
+            if new_config == *config {
+                info!("config reloaded, nothing changed");
+                return false;
+            }
+            for change in config.diff(&new_config) {
+                info!("config changed {}", change);
+            }
+            *config = new_config;
+            log_display_profile(config);
+            true
+        }
         Err(err) => {
-            warn!(
-                "failed to open TCP connection to Kindle, attempting recovery: {}",
+            error!(
+                "keeping previous config, {}: {}",
+                source.path().display(),
                 err
             );
-            if let Ok(kindle_opt) = usb::get_kindle() {
-                if let Some(kindle) = kindle_opt {
-                    // if let Err(err) = usb::reset_kindle(&kindle) {
-                    //     error!("Couldn't reset Kindle USB: {:?}", err);
-                    // }
-                } else {
-                    warn!("Kindle isn't connected!");
-                    return;
-                }
-            } else {
-                warn!("error using libusb, proceeding blindly");
-            }
-            if network::try_recover(handle).await.is_ok() {
-                if let Ok(ssh_tcp_stream) = open_tcp_connection() {
-                    ssh_tcp_stream
-                } else {
-                    warn!("could not connect after recovery attempt");
-                    return;
-                }
-            } else {
-                warn!("Kindle is disconnected or USB needs reset");
-                return;
-            }
+            false
         }
-    };
-    let mut ssh_session = if let Ok(cloned_ssh_tcp_stream) = ssh_tcp_stream.try_clone() {
-        open_ssh_session(cloned_ssh_tcp_stream)
-            .expect("ssh handshake failed, is the password correct?")
-    } else {
-        return;
-    };
+    }
+}
 
-    eips_show_image(&mut ssh_session, png, now.minute() == 0)
-        .expect("failed to send image to Kindle");
+fn log_display_profile(config: &Config) {
+    let profile = config.display.profile();
+    info!(
+        "driving {:?} panel: {}x{} at {} DPI, {}-bit grayscale, rotated {} degrees",
+        config.display.profile,
+        profile.width,
+        profile.height,
+        profile.dpi,
+        profile.gray_depth,
+        profile.orientation.degrees()
+    );
+}
 
-    if now.minute() == 0 && !night_time(&now) {
-        let (_, hour12) = now.hour12();
-        play_audio_for_hour(&mut ssh_session, now.hour(), hour12)
-            .expect("failed to play hourly tune");
-    }
-    ssh_session
-        .disconnect(None, "done sending commands", None)
-        .unwrap();
-    if let Err(err) = ssh_tcp_stream.shutdown(std::net::Shutdown::Both) {
-        warn!("error shutting down tcp connection, is this macOS? {}", err);
-    }
+fn start_of_next_minute<Tz: TimeZone>(now: DateTime<Tz>) -> DateTime<Tz> {
+    let one_minute = Duration::minutes(1);
+    now.duration_trunc(one_minute).unwrap() + one_minute
 }
 
-async fn generate_image(current_metar: Option<&Metar<'_>>, now: &DateTime<Local>) -> Vec<u8> {
+async fn generate_image(
+    config: &Config,
+    profile: &DisplayProfile,
+    current_metar: Option<&Metar<'_>>,
+    telemetry: Option<&Telemetry>,
+    now: &DateTime<Local>,
+) -> Frame {
     debug!("Current metar parsed {:?}", current_metar);
-    let surf = create_surface().expect("failed to create cairo surface");
-    let ctx = create_context(&surf);
-    draw_clock(&ctx, now, current_metar);
-    let png = write_surface_to_png(&surf);
-    png
+    render_frame(config, profile, |ctx, canvas| {
+        draw_clock(ctx, canvas, config, now, current_metar, telemetry)
+    })
 }
 
-fn night_time(now: &DateTime<Local>) -> bool {
-    now.hour() < 7 || now.hour() > 22
+fn render_frame(
+    config: &Config,
+    profile: &DisplayProfile,
+    draw: impl FnOnce(&Context, &Canvas) -> Vec<Zone>,
+) -> Frame {
+    let surf = create_surface(profile).expect("failed to create cairo surface");
+    let ctx = create_context(&surf, profile);
+    let zones = draw(&ctx, &profile.canvas())
+        .iter()
+        .map(|zone| {
+            let region = zone_to_region(&ctx, zone, profile.width, profile.height);
+            (region, zone.content)
+        })
+        .collect();
+    let convert_start = std::time::Instant::now();
+    let frame = surface_to_frame(&surf, profile.gray_depth, &config.display);
+    debug!("converted frame in {:?}", convert_start.elapsed());
+    frame.with_zones(zones)
 }
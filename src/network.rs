@@ -3,28 +3,28 @@ use ipnetwork::IpNetwork;
 use netlink_packet_route::rtnl::AddressMessage;
 use rtnetlink::{Error, Handle};
 
-use crate::{KINDLE_INTERFACE, PI_IP_ADDRESS};
+use crate::config::NetworkConfig;
 
-pub async fn setup_if_down(handle: &Handle) -> Result<(), Error> {
-    let ip_network = IpNetwork::new(PI_IP_ADDRESS, 24).unwrap();
-    add_address(&handle, ip_network).await?;
-    link_up(&handle).await
+pub async fn setup_if_down(handle: &Handle, config: &NetworkConfig) -> Result<(), Error> {
+    let ip_network = IpNetwork::new(config.pi_ip_address, 24).unwrap();
+    add_address(&handle, &config.interface, ip_network).await?;
+    link_up(&handle, &config.interface).await
 }
 
-pub async fn try_recover(handle: &Handle) -> Result<(), Error> {
-    let ip_network = IpNetwork::new(PI_IP_ADDRESS, 24).unwrap();
+pub async fn try_recover(handle: &Handle, config: &NetworkConfig) -> Result<(), Error> {
+    let ip_network = IpNetwork::new(config.pi_ip_address, 24).unwrap();
     del_address(&handle, ip_network).await?;
-    link_down(&handle, ip_network).await?;
-    add_address(&handle, ip_network).await?;
-    link_up(&handle).await
+    link_down(&handle, &config.interface, ip_network).await?;
+    add_address(&handle, &config.interface, ip_network).await?;
+    link_up(&handle, &config.interface).await
 }
 
-async fn add_address(handle: &Handle, ip: IpNetwork) -> Result<(), Error> {
+async fn add_address(handle: &Handle, interface: &str, ip: IpNetwork) -> Result<(), Error> {
     if get_address(handle, ip).await?.is_none() {
         let mut links = handle
             .link()
             .get()
-            .set_name_filter(KINDLE_INTERFACE.to_string())
+            .set_name_filter(interface.to_string())
             .execute();
         if let Some(link) = links.try_next().await? {
             handle
@@ -37,11 +37,11 @@ async fn add_address(handle: &Handle, ip: IpNetwork) -> Result<(), Error> {
     Ok(())
 }
 
-async fn link_up(handle: &Handle) -> Result<(), Error> {
+async fn link_up(handle: &Handle, interface: &str) -> Result<(), Error> {
     let mut links = handle
         .link()
         .get()
-        .set_name_filter(KINDLE_INTERFACE.to_string())
+        .set_name_filter(interface.to_string())
         .execute();
     if let Some(link) = links.try_next().await? {
         handle.link().set(link.header.index).up().execute().await?
@@ -68,11 +68,11 @@ async fn get_address(handle: &Handle, ip: IpNetwork) -> Result<Option<AddressMes
         .await
 }
 
-async fn link_down(handle: &Handle, ip: IpNetwork) -> Result<(), Error> {
+async fn link_down(handle: &Handle, interface: &str, ip: IpNetwork) -> Result<(), Error> {
     let mut links = handle
         .link()
         .get()
-        .set_name_filter(KINDLE_INTERFACE.to_string())
+        .set_name_filter(interface.to_string())
         .execute();
     if let Some(link) = links.try_next().await? {
         handle
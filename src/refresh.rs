@@ -0,0 +1,247 @@
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use std::path::PathBuf;
+
+use crate::frame::{Frame, Update};
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RefreshConfig {
+    /// Send only the regions that changed since the last frame
+    pub partial_updates: bool,
+    /// Send the whole frame instead once this fraction of it has changed
+    pub max_partial_fraction: f64,
+    /// Flash after this many updates without one, ghosting builds up with each
+    pub max_updates_between_flashes: u32,
+    /// Flash when at least this fraction of pixels changed
+    pub flash_changed_fraction: f64,
+    /// Flash whenever the art changes, it is the part most prone to ghosting
+    pub flash_on_art_change: bool,
+    /// Hour of the day to cycle the panel through black and white, if any
+    pub cleanse_hour: Option<u32>,
+    /// Read the display back after each update to check it shows what was sent
+    pub verify: bool,
+    /// Share of pixels that may differ before the display counts as not showing the frame
+    pub max_mismatch: f64,
+    /// Send the whole frame again with a flash when the display doesn't show it
+    pub repush_on_mismatch: bool,
+    /// Where to save what the display showed instead, for a look at what took over
+    pub mismatch_png: Option<PathBuf>,
+}
+
+impl Default for RefreshConfig {
+    fn default() -> Self {
+        Self {
+            partial_updates: true,
+            max_partial_fraction: 0.5,
+            max_updates_between_flashes: 60,
+            flash_changed_fraction: 0.5,
+            flash_on_art_change: true,
+            cleanse_hour: None,
+            verify: false,
+            max_mismatch: 0.05,
+            repush_on_mismatch: true,
+            mismatch_png: None,
+        }
+    }
+}
+
+/// What is on the display and how it got there
+#[derive(Debug, Default)]
+pub struct RefreshState {
+    last_frame: Option<Frame>,
+    updates_since_flash: u32,
+    last_art: Option<u32>,
+    last_cleanse: Option<Date<Local>>,
+}
+
+impl RefreshState {
+    /// Decides how much of `next` to send and whether to flash while doing it
+    pub fn plan(
+        &self,
+        config: &RefreshConfig,
+        next: &Frame,
+        now: &DateTime<Local>,
+        art: u32,
+    ) -> Update {
+        let last = match &self.last_frame {
+            Some(last) if last.full_region() == next.full_region() => last,
+            _ => {
+                debug!("nothing known to be on the display, flashing");
+                return Update::Full { flash: true };
+            }
+        };
+        if config.cleanse_hour == Some(now.hour()) && self.last_cleanse != Some(now.date()) {
+            return Update::Cleanse;
+        }
+        if config.flash_on_art_change && self.last_art != Some(art) {
+            debug!("art changed, flashing");
+            return Update::Full { flash: true };
+        }
+        if self.updates_since_flash >= config.max_updates_between_flashes {
+            debug!(
+                "{} updates since last flash, flashing",
+                self.updates_since_flash
+            );
+            return Update::Full { flash: true };
+        }
+        let changed_fraction = next.changed_fraction(last);
+        if changed_fraction == 0. {
+            return Update::Unchanged;
+        }
+        if changed_fraction >= config.flash_changed_fraction {
+            debug!(
+                "{:.0}% of pixels changed, flashing",
+                changed_fraction * 100.
+            );
+            return Update::Full { flash: true };
+        }
+        if !config.partial_updates {
+            return Update::Full { flash: false };
+        }
+        let regions = next.dirty_regions(last);
+        let dirty_area: usize = regions.iter().map(|region| region.area()).sum();
+        if dirty_area as f64 > next.full_region().area() as f64 * config.max_partial_fraction {
+            Update::Full { flash: false }
+        } else {
+            Update::Partial(regions)
+        }
+    }
+
+    /// Records that `update` brought the display to `frame`
+    pub fn record(&mut self, update: &Update, frame: Frame, now: &DateTime<Local>, art: u32) {
+        match update {
+            Update::Unchanged => return,
+            Update::Cleanse => {
+                self.last_cleanse = Some(now.date());
+                self.updates_since_flash = 0;
+            }
+            Update::Full { flash: true } => self.updates_since_flash = 0,
+            Update::Full { flash: false } | Update::Partial(_) => self.updates_since_flash += 1,
+        }
+        self.last_frame = Some(frame);
+        self.last_art = Some(art);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: usize = 100;
+
+    /// A white frame with its first `rows` rows black
+    fn frame(rows: usize) -> Frame {
+        let mut levels = vec![15; SIZE * SIZE];
+        levels[..rows * SIZE]
+            .iter_mut()
+            .for_each(|level| *level = 0);
+        Frame::new(SIZE, SIZE, 4, levels)
+    }
+
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        Local.ymd(2026, 10, day).and_hms(hour, minute, 0)
+    }
+
+    /// State after `frame` was shown with a flash
+    fn showing(frame: Frame) -> RefreshState {
+        let mut state = RefreshState::default();
+        state.record(&Update::Full { flash: true }, frame, &at(1, 12, 0), 1);
+        state
+    }
+
+    #[test]
+    fn flashes_when_nothing_is_known() {
+        let update =
+            RefreshState::default().plan(&RefreshConfig::default(), &frame(0), &at(1, 12, 1), 1);
+        assert_eq!(update, Update::Full { flash: true });
+    }
+
+    #[test]
+    fn flashes_when_the_art_changes() {
+        let update = showing(frame(0)).plan(&RefreshConfig::default(), &frame(0), &at(1, 12, 1), 2);
+        assert_eq!(update, Update::Full { flash: true });
+    }
+
+    #[test]
+    fn nothing_changed_sends_nothing() {
+        let update = showing(frame(0)).plan(&RefreshConfig::default(), &frame(0), &at(1, 12, 1), 1);
+        assert_eq!(update, Update::Unchanged);
+    }
+
+    #[test]
+    fn small_changes_send_only_their_regions() {
+        let update = showing(frame(0)).plan(&RefreshConfig::default(), &frame(1), &at(1, 12, 1), 1);
+        assert_eq!(update, Update::Partial(frame(1).dirty_regions(&frame(0))));
+    }
+
+    #[test]
+    fn flashes_after_too_many_updates_without_one() {
+        let config = RefreshConfig {
+            max_updates_between_flashes: 3,
+            ..RefreshConfig::default()
+        };
+        let mut state = showing(frame(0));
+        for rows in 1..=3 {
+            let now = at(1, 12, rows as u32);
+            let update = state.plan(&config, &frame(rows), &now, 1);
+            assert!(matches!(update, Update::Partial(_)), "{:?}", update);
+            state.record(&update, frame(rows), &now, 1);
+        }
+        let update = state.plan(&config, &frame(4), &at(1, 12, 4), 1);
+        assert_eq!(update, Update::Full { flash: true });
+    }
+
+    #[test]
+    fn flashes_when_enough_pixels_changed() {
+        let config = RefreshConfig {
+            flash_changed_fraction: 0.3,
+            ..RefreshConfig::default()
+        };
+        let state = showing(frame(0));
+        assert_eq!(
+            state.plan(&config, &frame(30), &at(1, 12, 1), 1),
+            Update::Full { flash: true }
+        );
+        assert!(matches!(
+            state.plan(&config, &frame(29), &at(1, 12, 1), 1),
+            Update::Partial(_)
+        ));
+    }
+
+    #[test]
+    fn large_changes_send_the_whole_frame_without_flashing() {
+        let config = RefreshConfig {
+            max_partial_fraction: 0.2,
+            flash_changed_fraction: 1.,
+            ..RefreshConfig::default()
+        };
+        let update = showing(frame(0)).plan(&config, &frame(30), &at(1, 12, 1), 1);
+        assert_eq!(update, Update::Full { flash: false });
+    }
+
+    #[test]
+    fn cleanses_once_a_day_at_the_cleanse_hour() {
+        let config = RefreshConfig {
+            cleanse_hour: Some(3),
+            ..RefreshConfig::default()
+        };
+        let mut state = showing(frame(0));
+        assert_eq!(
+            state.plan(&config, &frame(0), &at(1, 2, 59), 1),
+            Update::Unchanged
+        );
+        let update = state.plan(&config, &frame(0), &at(1, 3, 0), 1);
+        assert_eq!(update, Update::Cleanse);
+        state.record(&update, frame(0), &at(1, 3, 0), 1);
+        assert_eq!(
+            state.plan(&config, &frame(0), &at(1, 3, 1), 1),
+            Update::Unchanged
+        );
+        assert_eq!(
+            state.plan(&config, &frame(0), &at(2, 3, 0), 1),
+            Update::Cleanse
+        );
+    }
+}
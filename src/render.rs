@@ -1,16 +1,23 @@
-use crate::{HEIGHT, WIDTH};
+use crate::config::DisplayConfig;
+use crate::display::DisplayProfile;
+use crate::dither::quantize;
+use crate::frame::{Frame, Region};
+use crate::waveform::Zone;
 
 use cairo::{Context, Error, FontFace, FontOptions, FontSlant, FontWeight, Format, ImageSurface};
 
-pub fn create_surface() -> Result<ImageSurface, Error> {
-    ImageSurface::create(Format::Rgb24, HEIGHT as i32, WIDTH as i32)
+/// The surface matches the framebuffer, see [create_context] for drawing on it upright
+pub fn create_surface(profile: &DisplayProfile) -> Result<ImageSurface, Error> {
+    ImageSurface::create(Format::Rgb24, profile.width as i32, profile.height as i32)
 }
 
-pub fn create_context(surf: &ImageSurface) -> Context {
+/// Context with the canvas of the profile mapped onto the framebuffer surface
+pub fn create_context(surf: &ImageSurface, profile: &DisplayProfile) -> Context {
+    let canvas = profile.canvas();
     let ctx = Context::new(surf).unwrap();
-    ctx.translate(HEIGHT as f64 / 2., WIDTH as f64 / 2.);
-    ctx.rotate(-90.0 * std::f64::consts::PI / 180.0);
-    ctx.translate(WIDTH as f64 / -2., HEIGHT as f64 / -2.);
+    ctx.translate(surf.width() as f64 / 2., surf.height() as f64 / 2.);
+    ctx.rotate(profile.orientation.degrees() as f64 * std::f64::consts::PI / 180.0);
+    ctx.translate(canvas.width / -2., canvas.height / -2.);
     ctx
 }
 
@@ -21,30 +28,135 @@ pub fn set_font(ctx: &Context, font_name: &str) {
     ctx.set_font_options(&font_opts);
 }
 
-pub fn write_surface_to_png(surf: &ImageSurface) -> Vec<u8> {
-    let mut png_data = Vec::with_capacity(WIDTH * HEIGHT * 3);
-    surf.write_to_png(&mut png_data).unwrap();
-    let mut png_data_slice = png_data.as_slice();
-    let mut grayscale_buf = vec![0; WIDTH * HEIGHT];
+/// Calibrates and quantizes the surface to the panel's gray depth
+pub fn surface_to_frame(surf: &ImageSurface, gray_depth: u8, config: &DisplayConfig) -> Frame {
+    let (width, height) = (surf.width() as usize, surf.height() as usize);
+    let mut grayscale_buf = surface_to_grayscale(surf);
+    config.calibration.apply(&mut grayscale_buf);
+    let levels = quantize(&grayscale_buf, width, gray_depth, config.dithering);
+    Frame::new(width, height, gray_depth, levels)
+}
+
+/// Smallest framebuffer region covering the zone, however the context is rotated
+pub fn zone_to_region(ctx: &Context, zone: &Zone, width: usize, height: usize) -> Region {
+    let corners = [
+        ctx.user_to_device(zone.x, zone.y),
+        ctx.user_to_device(zone.x + zone.width, zone.y),
+        ctx.user_to_device(zone.x, zone.y + zone.height),
+        ctx.user_to_device(zone.x + zone.width, zone.y + zone.height),
+    ];
+    let clamp = |value: f64, max: usize| (value.max(0.) as usize).min(max);
+    let left = clamp(
+        corners.iter().map(|c| c.0).fold(f64::MAX, f64::min).floor(),
+        width,
+    );
+    let right = clamp(
+        corners.iter().map(|c| c.0).fold(f64::MIN, f64::max).ceil(),
+        width,
+    );
+    let top = clamp(
+        corners.iter().map(|c| c.1).fold(f64::MAX, f64::min).floor(),
+        height,
+    );
+    let bottom = clamp(
+        corners.iter().map(|c| c.1).fold(f64::MIN, f64::max).ceil(),
+        height,
+    );
+    Region {
+        x: left,
+        y: top,
+        width: right - left,
+        height: bottom - top,
+    }
+}
+
+/// Averages the channels of each pixel, reading the surface's memory in place
+fn surface_to_grayscale(surf: &ImageSurface) -> Vec<u8> {
+    let (width, height) = (surf.width() as usize, surf.height() as usize);
+    let stride = surf.stride() as usize;
+    let mut grayscale_buf = Vec::with_capacity(width * height);
+    surf.with_data(|data| {
+        for row in data.chunks(stride).take(height) {
+            // Rgb24 pixels are native-endian u32s with the top byte unused
+            for pixel in row[..width * 4].chunks_exact(4) {
+                let [_, r, g, b] =
+                    u32::from_ne_bytes([pixel[0], pixel[1], pixel[2], pixel[3]]).to_be_bytes();
+                grayscale_buf.push(((r as u16 + g as u16 + b as u16) / 3) as u8);
+            }
+        }
+    })
+    .expect("failed to read cairo surface");
+    grayscale_buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::Model;
 
-    {
-        let decoder = png::Decoder::new(&mut png_data_slice);
-        let (info, mut reader) = decoder.read_info().unwrap();
+    use std::time::{Duration, Instant};
+
+    /// How grayscale was read before, by encoding the surface as an RGB PNG and decoding it again
+    fn surface_to_grayscale_via_png(surf: &ImageSurface) -> Vec<u8> {
+        let (width, height) = (surf.width() as usize, surf.height() as usize);
+        let mut png_data = Vec::with_capacity(width * height * 3);
+        surf.write_to_png(&mut png_data).unwrap();
+        let (info, mut reader) = png::Decoder::new(png_data.as_slice()).read_info().unwrap();
         let mut buf = vec![0; info.buffer_size()];
         reader.next_frame(&mut buf).unwrap();
-        for i in 0..(WIDTH * HEIGHT) {
-            let rgb = &buf[3 * i..3 * i + 3];
-            grayscale_buf[i] = ((rgb[0] as u16 + rgb[1] as u16 + rgb[2] as u16) / 3) as u8;
+        buf.chunks_exact(3)
+            .map(|rgb| ((rgb[0] as u16 + rgb[1] as u16 + rgb[2] as u16) / 3) as u8)
+            .collect()
+    }
+
+    fn time(runs: u32, mut read: impl FnMut() -> Vec<u8>) -> (Duration, Vec<u8>) {
+        let start = Instant::now();
+        let mut grayscale = vec![];
+        for _ in 0..runs {
+            grayscale = read();
+        }
+        (start.elapsed() / runs, grayscale)
+    }
+
+    /// A Kindle DX sized surface with a gradient across it
+    fn gradient_surface() -> ImageSurface {
+        let profile = Model::KindleDx.profile();
+        let surf = create_surface(&profile).unwrap();
+        {
+            let ctx = Context::new(&surf).unwrap();
+            let gradient = cairo::LinearGradient::new(0., 0., profile.width as f64, 0.);
+            gradient.add_color_stop_rgb(0., 0., 0., 0.);
+            gradient.add_color_stop_rgb(1., 1., 0.5, 1.);
+            ctx.set_source(&gradient).unwrap();
+            ctx.paint().unwrap();
         }
+        surf
+    }
+
+    #[test]
+    fn surface_to_grayscale_matches_png_round_trip() {
+        let surf = gradient_surface();
+        assert_eq!(
+            surface_to_grayscale(&surf),
+            surface_to_grayscale_via_png(&surf)
+        );
     }
 
-    let mut grayscale_png = Vec::with_capacity(WIDTH * HEIGHT);
-    {
-        let mut encoder = png::Encoder::new(&mut grayscale_png, HEIGHT as u32, WIDTH as u32);
-        encoder.set_color(png::ColorType::Grayscale);
-        encoder.set_depth(png::BitDepth::Eight);
-        let mut writer = encoder.write_header().unwrap();
-        writer.write_image_data(&grayscale_buf).unwrap();
+    /// Timings only mean something in release builds on a quiet machine, run with
+    /// `cargo test --release -- --ignored --nocapture surface_to_grayscale`
+    #[test]
+    #[ignore]
+    fn surface_to_grayscale_beats_png_round_trip() {
+        let surf = gradient_surface();
+        let (round_trip, _) = time(5, || surface_to_grayscale_via_png(&surf));
+        let (direct, _) = time(5, || surface_to_grayscale(&surf));
+        eprintln!(
+            "{}x{} surface: PNG round trip {:?}, direct read {:?}",
+            surf.width(),
+            surf.height(),
+            round_trip,
+            direct
+        );
+        assert!(direct < round_trip);
     }
-    grayscale_png
 }
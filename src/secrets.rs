@@ -0,0 +1,98 @@
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::config::ConfigError;
+
+/// A password or token, which prints as a placeholder so it can't end up in logs
+#[derive(Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Self(value.into())
+    }
+
+    /// The actual value, only for handing to whatever needs it
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecretsConfig {
+    /// TOML file of `name = "value"` pairs, readable only by its owner
+    pub file: Option<PathBuf>,
+}
+
+/// Where secrets are looked up, in order of preference:
+///
+/// 1. the `EINK_CLOCK_<NAME>` environment variable
+/// 2. a systemd credential called `<name>`, see `LoadCredential=` in systemd.exec(5)
+/// 3. the secrets file
+pub struct Secrets {
+    credentials_directory: Option<PathBuf>,
+    file: BTreeMap<String, String>,
+}
+
+impl Secrets {
+    pub fn load(config: &SecretsConfig) -> Result<Self, ConfigError> {
+        let file = match &config.file {
+            Some(path) => read_secrets_file(path)?,
+            None => BTreeMap::new(),
+        };
+        Ok(Self {
+            credentials_directory: env::var_os("CREDENTIALS_DIRECTORY").map(PathBuf::from),
+            file,
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Result<Option<Secret>, ConfigError> {
+        if let Ok(value) = env::var(format!("EINK_CLOCK_{}", name.to_uppercase())) {
+            return Ok(Some(Secret(value)));
+        }
+        if let Some(directory) = &self.credentials_directory {
+            match fs::read_to_string(directory.join(name)) {
+                Ok(value) => return Ok(Some(Secret(value.trim_end_matches('\n').to_owned()))),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(ConfigError::Io(err)),
+            }
+        }
+        Ok(self.file.get(name).cloned().map(Secret))
+    }
+}
+
+fn read_secrets_file(path: &Path) -> Result<BTreeMap<String, String>, ConfigError> {
+    let mode = fs::metadata(path)
+        .map_err(ConfigError::Io)?
+        .permissions()
+        .mode();
+    if mode & 0o077 != 0 {
+        return Err(ConfigError::Invalid(format!(
+            "secrets file {} can be read by others, restrict it with chmod 600",
+            path.display()
+        )));
+    }
+    let raw = fs::read_to_string(path).map_err(ConfigError::Io)?;
+    toml::from_str(&raw).map_err(ConfigError::Parse)
+}
@@ -0,0 +1,746 @@
+use chrono::prelude::*;
+use futures::future::{BoxFuture, FutureExt};
+use rtnetlink::Handle;
+use serde::{Deserialize, Serialize};
+use ssh2::Session;
+use tokio::time;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::command::CommandError;
+use crate::config::{Config, KindleConfig, NetworkConfig};
+use crate::display::{DetectedGeometry, Orientation};
+use crate::frame::{Frame, Region};
+use crate::kindle::*;
+use crate::local::LocalShell;
+use crate::ssh::*;
+use crate::telemetry::Telemetry;
+use crate::waveform::Waveform;
+use crate::{network, usb};
+
+/// Where a Kindle keeps uploaded chimes and images, in RAM to spare its flash
+const ASSET_DIRECTORY: &str = "/dev/shm/eink-clock";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SinkKind {
+    /// A Kindle reached over SSH, drawing with FBInk or `eips`
+    Ssh,
+    /// The Kindle eink-clock is running on, drawing with FBInk or `eips`
+    Local,
+    /// A Linux framebuffer device, or any file laid out like one
+    Framebuffer,
+    /// Every update saved as a PNG, for trying things out without hardware
+    PngDirectory,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    pub sink: SinkKind,
+    /// Framebuffer to write to, in its own pixel format, or a file to get one byte of gray per pixel
+    pub framebuffer: PathBuf,
+    /// Where the PNGs go for the `png-directory` sink
+    pub directory: PathBuf,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            sink: SinkKind::Ssh,
+            framebuffer: PathBuf::from("/dev/fb0"),
+            directory: PathBuf::from("frames"),
+        }
+    }
+}
+
+/// Somewhere frames can be shown
+pub trait DisplaySink {
+    /// Gets the display ready for a round of updates, e.g. by connecting to it
+    fn connect(&mut self) -> BoxFuture<'_, io::Result<()>>;
+
+    /// Framebuffer resolution and rotation, if the display can tell
+    fn framebuffer_geometry(&mut self) -> io::Result<Option<DetectedGeometry>> {
+        Ok(None)
+    }
+
+    /// Gets the regions of the frame onto the display ahead of showing them, where that saves time
+    fn stage(&mut self, _frame: &Frame, _regions: &[Region]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn show_frame(&mut self, frame: &Frame, flash: bool, waveform: Waveform) -> io::Result<()>;
+
+    /// Draws one region of the frame and leaves the rest of the display alone
+    fn show_region(&mut self, frame: &Frame, region: &Region, waveform: Waveform)
+        -> io::Result<()>;
+
+    /// Battery, temperature and the like, if the display is a device that reports them
+    fn telemetry(&mut self) -> io::Result<Option<Telemetry>> {
+        Ok(None)
+    }
+
+    /// What the display is showing, if it can be read back
+    fn read_back(&mut self) -> io::Result<Option<Frame>> {
+        Ok(None)
+    }
+
+    /// Blanks the display to white, if it has a way to
+    fn clear(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Plays a WAV file, if the display has a speaker
+    fn play_audio(&mut self, _volume: u8, _wav: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Ends the round of updates started by [DisplaySink::connect], connections worth keeping may stay open
+    fn disconnect(&mut self) {}
+
+    /// Keeps a connection left open by [DisplaySink::disconnect] from timing out, called every
+    /// `keepalive_interval` seconds while there is nothing to show
+    fn keep_alive(&mut self) {}
+
+    /// Takes on Kindle settings that a reload changed, for those that don't need a new sink.
+    /// See [KindleConfig::same_session]
+    fn update_kindle_config(&mut self, _kindle: &KindleConfig) {}
+
+    /// Sets the device's clock from this machine's if it has drifted, logging how far off it was
+    fn sync_clock(&mut self) {}
+
+    /// Puts back whatever was changed on the device to keep it showing the clock, on shutdown
+    fn release(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        futures::future::ready(Ok(())).boxed()
+    }
+}
+
+pub fn from_config(config: &Config, handle: &Handle) -> Box<dyn DisplaySink> {
+    match config.output.sink {
+        SinkKind::Ssh => Box::new(SshSink {
+            kindle: config.kindle.clone(),
+            network: config.network.clone(),
+            handle: handle.clone(),
+            connection: None,
+            handshakes: 0,
+            screen: Screen::default(),
+            assets: AssetCache::new(ASSET_DIRECTORY),
+            taken_over: false,
+        }),
+        SinkKind::Local => Box::new(LocalSink {
+            kindle: config.kindle.clone(),
+            shell: LocalShell {
+                timeout: Duration::from_millis(config.kindle.command_timeout),
+            },
+            screen: Screen::default(),
+            assets: AssetCache::new(ASSET_DIRECTORY),
+            taken_over: false,
+        }),
+        SinkKind::Framebuffer => Box::new(FramebufferSink::new(config.output.framebuffer.clone())),
+        SinkKind::PngDirectory => Box::new(PngDirectorySink::new(config.output.directory.clone())),
+    }
+}
+
+fn not_connected() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "display is not connected")
+}
+
+/// The Kindle on the other end of the USB network link, over one SSH session kept open between updates
+pub struct SshSink {
+    kindle: KindleConfig,
+    network: NetworkConfig,
+    handle: Handle,
+    connection: Option<(TcpStream, Session)>,
+    /// Sessions opened so far, each one a full handshake
+    handshakes: u32,
+    screen: Screen,
+    assets: AssetCache,
+    /// Whether the framework and screensaver have to be put back on shutdown
+    taken_over: bool,
+}
+
+fn session(connection: &mut Option<(TcpStream, Session)>) -> io::Result<&mut Session> {
+    connection
+        .as_mut()
+        .map(|(_, session)| session)
+        .ok_or_else(not_connected)
+}
+
+impl SshSink {
+    /// A session that failed to run a command is not trusted with the next one, while a
+    /// command that merely exited with an error says nothing about the session
+    fn checked<T>(&mut self, result: Result<T, CommandError>) -> io::Result<T> {
+        if let Err(CommandError::Io(_)) = result {
+            self.close();
+        }
+        Ok(result?)
+    }
+
+    fn close(&mut self) {
+        self.screen.forget_staged();
+        self.assets.forget();
+        if let Some((ssh_tcp_stream, ssh_session)) = self.connection.take() {
+            if let Err(err) = ssh_session.disconnect(None, "done sending commands", None) {
+                debug!("error disconnecting SSH session: {}", err);
+            }
+            if let Err(err) = ssh_tcp_stream.shutdown(std::net::Shutdown::Both) {
+                warn!("error shutting down tcp connection, is this macOS? {}", err);
+            }
+        }
+    }
+
+    /// Brings up the USB network link and opens an SSH session, attempting recovery on failure
+    async fn connect_to_kindle(&self) -> Option<(TcpStream, Session)> {
+        let timeout = Duration::from_millis(self.kindle.command_timeout);
+        match time::timeout(timeout, network::setup_if_down(&self.handle, &self.network)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => {
+                error!("failed to set up network via rtnetlink: {}", err);
+                return None;
+            }
+            Err(_) => {
+                warn!("timed out setting up network");
+                return None;
+            }
+        }
+
+        let ssh_tcp_stream = match open_tcp_connection(&self.kindle) {
+            Ok(ssh_tcp_stream) => ssh_tcp_stream,
+            Err(err) => {
+                warn!(
+                    "failed to open TCP connection to Kindle, attempting recovery: {}",
+                    err
+                );
+                if let Ok(kindle_opt) = usb::get_kindle(&self.kindle) {
+                    if let Some(kindle) = kindle_opt {
+                        // if let Err(err) = usb::reset_kindle(&kindle) {
+                        //     error!("Couldn't reset Kindle USB: {:?}", err);
+                        // }
+                    } else {
+                        warn!("Kindle isn't connected!");
+                        return None;
+                    }
+                } else {
+                    warn!("error using libusb, proceeding blindly");
+                }
+                if let Ok(Ok(())) =
+                    time::timeout(timeout, network::try_recover(&self.handle, &self.network)).await
+                {
+                    if let Ok(ssh_tcp_stream) = open_tcp_connection(&self.kindle) {
+                        ssh_tcp_stream
+                    } else {
+                        warn!("could not connect after recovery attempt");
+                        return None;
+                    }
+                } else {
+                    warn!("Kindle is disconnected or USB needs reset");
+                    return None;
+                }
+            }
+        };
+        let ssh_session = if let Ok(cloned_ssh_tcp_stream) = ssh_tcp_stream.try_clone() {
+            match open_ssh_session(cloned_ssh_tcp_stream, &self.kindle) {
+                Ok(ssh_session) => ssh_session,
+                Err(err) => {
+                    error!("could not open SSH session to Kindle: {}", err);
+                    return None;
+                }
+            }
+        } else {
+            return None;
+        };
+
+        Some((ssh_tcp_stream, ssh_session))
+    }
+}
+
+impl DisplaySink for SshSink {
+    fn connect(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        async move {
+            if let Some((_, session)) = &mut self.connection {
+                if session_is_alive(session, self.kindle.connect_timeout as u32) {
+                    debug!("reusing SSH session, {} handshakes so far", self.handshakes);
+                    return Ok(());
+                }
+                warn!("SSH session to Kindle went dead, reconnecting");
+                self.close();
+            }
+            self.connection = self.connect_to_kindle().await;
+            match self.connection {
+                Some(_) => {
+                    self.handshakes += 1;
+                    info!(
+                        "opened SSH session to Kindle, handshake #{}",
+                        self.handshakes
+                    );
+                    // Again on every new session, the Kindle may have rebooted in between
+                    let result = take_over(session(&mut self.connection)?, &self.kindle);
+                    match self.checked(result) {
+                        Ok(()) => self.taken_over = true,
+                        Err(err) => warn!(
+                            "failed to keep the Kindle's framework off the screen: {}",
+                            err
+                        ),
+                    }
+                    if self.kindle.sync_clock {
+                        self.sync_clock();
+                    }
+                    Ok(())
+                }
+                None => Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "could not reach the Kindle",
+                )),
+            }
+        }
+        .boxed()
+    }
+
+    fn framebuffer_geometry(&mut self) -> io::Result<Option<DetectedGeometry>> {
+        let result = query_framebuffer_geometry(session(&mut self.connection)?);
+        self.checked(result)
+    }
+
+    fn stage(&mut self, frame: &Frame, regions: &[Region]) -> io::Result<()> {
+        let result = self.screen.stage(
+            session(&mut self.connection)?,
+            &mut self.assets,
+            frame,
+            regions,
+        );
+        self.checked(result)
+    }
+
+    fn show_frame(&mut self, frame: &Frame, flash: bool, waveform: Waveform) -> io::Result<()> {
+        let result = self
+            .screen
+            .show_image(session(&mut self.connection)?, frame, flash, waveform);
+        self.checked(result)
+    }
+
+    fn show_region(
+        &mut self,
+        frame: &Frame,
+        region: &Region,
+        waveform: Waveform,
+    ) -> io::Result<()> {
+        let result =
+            self.screen
+                .show_region(session(&mut self.connection)?, frame, region, waveform);
+        self.checked(result)
+    }
+
+    fn telemetry(&mut self) -> io::Result<Option<Telemetry>> {
+        let result = query_telemetry(session(&mut self.connection)?).map(Some);
+        self.checked(result)
+    }
+
+    fn read_back(&mut self) -> io::Result<Option<Frame>> {
+        let result = read_framebuffer(session(&mut self.connection)?);
+        self.checked(result)
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        let result = self.screen.clear(session(&mut self.connection)?);
+        self.checked(result)
+    }
+
+    fn play_audio(&mut self, volume: u8, wav: &[u8]) -> io::Result<()> {
+        let session = session(&mut self.connection)?;
+        let assets = &mut self.assets;
+        let result = amixer_set_master_volume(session, volume)
+            .and_then(|_| aplay_audio_nonblocking(session, assets, wav));
+        self.checked(result)
+    }
+
+    fn sync_clock(&mut self) {
+        let max_drift = self.kindle.max_clock_drift;
+        let result = match session(&mut self.connection) {
+            Ok(session) => sync_clock(session, max_drift),
+            Err(err) => Err(CommandError::Io(err)),
+        };
+        match self.checked(result) {
+            Ok(offset) if offset.unsigned_abs() > max_drift as u64 => {
+                info!("Kindle clock was {:+} s off, set it from ours", offset)
+            }
+            Ok(offset) => info!("Kindle clock is {:+} s off", offset),
+            Err(err) => warn!("failed to sync the Kindle's clock: {}", err),
+        }
+    }
+
+    fn update_kindle_config(&mut self, kindle: &KindleConfig) {
+        self.kindle = kindle.clone();
+    }
+
+    fn keep_alive(&mut self) {
+        if let Some((_, session)) = &mut self.connection {
+            if let Err(err) = session.keepalive_send() {
+                warn!("SSH keepalive failed, closing the session: {}", err);
+                self.close();
+            }
+        }
+    }
+
+    fn release(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        async move {
+            if !self.taken_over {
+                return Ok(());
+            }
+            self.connect().await?;
+            let result = hand_back(session(&mut self.connection)?, &self.kindle);
+            self.checked(result)?;
+            self.taken_over = false;
+            info!("handed the Kindle's screen back to its framework");
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+impl Drop for SshSink {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Runs the same commands as [SshSink] on this device, with no link to bring up
+pub struct LocalSink {
+    kindle: KindleConfig,
+    shell: LocalShell,
+    screen: Screen,
+    assets: AssetCache,
+    taken_over: bool,
+}
+
+impl DisplaySink for LocalSink {
+    fn update_kindle_config(&mut self, kindle: &KindleConfig) {
+        self.shell.timeout = Duration::from_millis(kindle.command_timeout);
+        self.kindle = kindle.clone();
+    }
+
+    fn connect(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        if !self.taken_over {
+            match take_over(&mut self.shell, &self.kindle) {
+                Ok(()) => self.taken_over = true,
+                Err(err) => warn!(
+                    "failed to keep the Kindle's framework off the screen: {}",
+                    err
+                ),
+            }
+        }
+        futures::future::ready(Ok(())).boxed()
+    }
+
+    fn framebuffer_geometry(&mut self) -> io::Result<Option<DetectedGeometry>> {
+        Ok(query_framebuffer_geometry(&mut self.shell)?)
+    }
+
+    fn stage(&mut self, frame: &Frame, regions: &[Region]) -> io::Result<()> {
+        Ok(self
+            .screen
+            .stage(&mut self.shell, &mut self.assets, frame, regions)?)
+    }
+
+    fn show_frame(&mut self, frame: &Frame, flash: bool, waveform: Waveform) -> io::Result<()> {
+        Ok(self
+            .screen
+            .show_image(&mut self.shell, frame, flash, waveform)?)
+    }
+
+    fn show_region(
+        &mut self,
+        frame: &Frame,
+        region: &Region,
+        waveform: Waveform,
+    ) -> io::Result<()> {
+        Ok(self
+            .screen
+            .show_region(&mut self.shell, frame, region, waveform)?)
+    }
+
+    fn telemetry(&mut self) -> io::Result<Option<Telemetry>> {
+        Ok(Some(query_telemetry(&mut self.shell)?))
+    }
+
+    fn read_back(&mut self) -> io::Result<Option<Frame>> {
+        Ok(read_framebuffer(&mut self.shell)?)
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        Ok(self.screen.clear(&mut self.shell)?)
+    }
+
+    fn play_audio(&mut self, volume: u8, wav: &[u8]) -> io::Result<()> {
+        amixer_set_master_volume(&mut self.shell, volume)?;
+        Ok(aplay_audio_nonblocking(
+            &mut self.shell,
+            &mut self.assets,
+            wav,
+        )?)
+    }
+
+    fn release(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        let result = if self.taken_over {
+            hand_back(&mut self.shell, &self.kindle).map_err(io::Error::from)
+        } else {
+            Ok(())
+        };
+        self.taken_over = false;
+        futures::future::ready(result).boxed()
+    }
+}
+
+/// Writes frames straight into a framebuffer in its own pixel format, which has no notion of
+/// flashing or waveforms
+pub struct FramebufferSink {
+    path: PathBuf,
+    file: Option<File>,
+    layout: Option<FramebufferLayout>,
+}
+
+/// How a framebuffer lays out its pixels, plain files get one byte of gray per pixel
+struct FramebufferLayout {
+    /// Name of the framebuffer under [SYSFS_GRAPHICS], unless the path is a plain file
+    device: Option<String>,
+    bits_per_pixel: usize,
+    /// Bytes per row, if the kernel says. Otherwise rows aren't padded
+    stride: Option<usize>,
+    /// Visible resolution and rotation, if the kernel says
+    geometry: Option<DetectedGeometry>,
+}
+
+impl FramebufferLayout {
+    fn query(path: &Path) -> io::Result<Self> {
+        // Only devices have attributes, a plain file called fb0 elsewhere doesn't
+        let device = path
+            .strip_prefix("/dev")
+            .ok()
+            .and_then(|name| name.to_str())
+            .filter(|name| Path::new(SYSFS_GRAPHICS).join(name).is_dir())
+            .map(str::to_owned);
+        let attribute = |name: &str| {
+            let device = device.as_ref()?;
+            fs::read_to_string(Path::new(SYSFS_GRAPHICS).join(device).join(name)).ok()
+        };
+        let number = |name: &str| attribute(name)?.trim().parse::<usize>().ok();
+        let bits_per_pixel = number("bits_per_pixel").unwrap_or(8);
+        let stride = number("stride");
+        let geometry = attribute("modes")
+            .and_then(|modes| parse_framebuffer_mode(&modes))
+            .map(|(width, height)| DetectedGeometry {
+                width,
+                height,
+                rotation: number("rotate")
+                    .map_or(Orientation::Rotate0, Orientation::from_quarter_turns),
+            });
+        if ![4, 8, 16, 24, 32].contains(&bits_per_pixel) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "{} has {} bits per pixel, which isn't supported",
+                    path.display(),
+                    bits_per_pixel
+                ),
+            ));
+        }
+        Ok(Self {
+            device,
+            bits_per_pixel,
+            stride,
+            geometry,
+        })
+    }
+
+    /// Widens the region to whole bytes, where pixels are smaller than that
+    fn align(&self, region: &Region, frame_width: usize) -> Region {
+        if self.bits_per_pixel != 4 {
+            return *region;
+        }
+        let left = region.x & !1;
+        let right = ((region.x + region.width + 1) & !1).min(frame_width);
+        Region {
+            x: left,
+            width: right - left,
+            ..*region
+        }
+    }
+
+    /// A row of 8-bit grays in the framebuffer's pixel format
+    fn encode(&self, row: &[u8]) -> Vec<u8> {
+        match self.bits_per_pixel {
+            // As on the Kindle 3 and DX, two pixels per byte and 0 is white
+            4 => {
+                let nibble = |gray: u8| ((255 - gray as u16 + 8) / 17) as u8;
+                row.chunks(2)
+                    .map(|pair| nibble(pair[0]) << 4 | pair.get(1).map_or(0, |&gray| nibble(gray)))
+                    .collect()
+            }
+            8 => row.to_vec(),
+            16 => row
+                .iter()
+                .flat_map(|&gray| {
+                    let (five, six) = (gray as u16 >> 3, gray as u16 >> 2);
+                    (five << 11 | six << 5 | five).to_ne_bytes()
+                })
+                .collect(),
+            24 => row.iter().flat_map(|&gray| [gray, gray, gray]).collect(),
+            _ => row
+                .iter()
+                .flat_map(|&gray| [gray, gray, gray, 0xff])
+                .collect(),
+        }
+    }
+
+    /// Writes the pan offset back to sysfs, which makes drivers that don't watch the memory,
+    /// like those for e-ink panels, update the screen. Others don't need it and may refuse
+    fn refresh(&self) {
+        if let Some(device) = &self.device {
+            let pan = Path::new(SYSFS_GRAPHICS).join(device).join("pan");
+            if let Err(err) =
+                fs::read_to_string(&pan).and_then(|offset| fs::write(&pan, offset.trim()))
+            {
+                debug!("could not refresh {} by panning: {}", device, err);
+            }
+        }
+    }
+}
+
+impl FramebufferSink {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            file: None,
+            layout: None,
+        }
+    }
+
+    fn write_region(&mut self, frame: &Frame, region: &Region) -> io::Result<()> {
+        let file = self.file.as_mut().ok_or_else(not_connected)?;
+        let layout = self.layout.as_ref().ok_or_else(not_connected)?;
+        if let Some(geometry) = &layout.geometry {
+            // Rows would run on into the next line of the framebuffer
+            if frame.width > geometry.width || frame.height > geometry.height {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{}x{} frame doesn't fit the {}x{} framebuffer",
+                        frame.width, frame.height, geometry.width, geometry.height
+                    ),
+                ));
+            }
+        }
+        let region = layout.align(region, frame.width);
+        let stride = layout
+            .stride
+            .unwrap_or((frame.width * layout.bits_per_pixel).div_ceil(8));
+        let gray = frame.to_gray(&region);
+        for (row, y) in gray.chunks(region.width).zip(region.y..) {
+            let offset = y * stride + region.x * layout.bits_per_pixel / 8;
+            file.seek(SeekFrom::Start(offset as u64))?;
+            file.write_all(&layout.encode(row))?;
+        }
+        file.flush()?;
+        layout.refresh();
+        Ok(())
+    }
+
+    fn open(&mut self) -> io::Result<()> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)?;
+        self.layout = Some(FramebufferLayout::query(&self.path)?);
+        self.file = Some(file);
+        Ok(())
+    }
+}
+
+impl DisplaySink for FramebufferSink {
+    fn connect(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        let opened = self.open();
+        async move { opened }.boxed()
+    }
+
+    fn framebuffer_geometry(&mut self) -> io::Result<Option<DetectedGeometry>> {
+        let layout = self.layout.as_ref().ok_or_else(not_connected)?;
+        Ok(layout.geometry)
+    }
+
+    fn show_frame(&mut self, frame: &Frame, _flash: bool, _waveform: Waveform) -> io::Result<()> {
+        self.write_region(frame, &frame.full_region())
+    }
+
+    fn show_region(
+        &mut self,
+        frame: &Frame,
+        region: &Region,
+        _waveform: Waveform,
+    ) -> io::Result<()> {
+        self.write_region(frame, region)
+    }
+
+    fn disconnect(&mut self) {
+        self.file = None;
+    }
+}
+
+/// Saves each update as its own PNG, named after when it was sent and what it covers
+pub struct PngDirectorySink {
+    directory: PathBuf,
+    count: usize,
+}
+
+impl PngDirectorySink {
+    pub fn new(directory: PathBuf) -> Self {
+        Self {
+            directory,
+            count: 0,
+        }
+    }
+
+    fn save(&mut self, description: &str, png: &[u8]) -> io::Result<()> {
+        self.count += 1;
+        let path = self.directory.join(format!(
+            "{}-{:04}-{}.png",
+            Local::now().format("%Y%m%dT%H%M%S"),
+            self.count,
+            description
+        ));
+        debug!("saving {}", path.display());
+        fs::write(path, png)
+    }
+}
+
+impl DisplaySink for PngDirectorySink {
+    fn connect(&mut self) -> BoxFuture<'_, io::Result<()>> {
+        let created = fs::create_dir_all(&self.directory);
+        async move { created }.boxed()
+    }
+
+    fn show_frame(&mut self, frame: &Frame, flash: bool, waveform: Waveform) -> io::Result<()> {
+        let description = format!(
+            "full-{}-{:?}",
+            if flash { "flash" } else { "noflash" },
+            waveform
+        );
+        self.save(
+            &description.to_lowercase(),
+            &frame.to_png(&frame.full_region()),
+        )
+    }
+
+    fn show_region(
+        &mut self,
+        frame: &Frame,
+        region: &Region,
+        waveform: Waveform,
+    ) -> io::Result<()> {
+        let description = format!("region-{}-{}-{:?}", region.x, region.y, waveform);
+        self.save(&description.to_lowercase(), &frame.to_png(region))
+    }
+}
@@ -1,84 +1,151 @@
-use crate::{
-    KINDLE_CONNECT_TIMEOUT, KINDLE_IP_ADDRESS, KINDLE_PASSWORD, KINDLE_SSH_PORT, KINDLE_USERNAME,
-};
+use crate::command::{Command, CommandError, Output};
+use crate::config::{KindleConfig, SshAuth};
+use crate::kindle::Shell;
 
-use ssh2::{Session, Channel};
-use std::io::Write;
+use ssh2::{CheckResult, HashType, KnownHostFileKind, Session};
+use std::io::{self, Write};
 use std::net::{SocketAddr, TcpStream};
 use std::path::Path;
 use std::time::Duration;
 
-pub fn open_tcp_connection() -> std::io::Result<TcpStream> {
-    let address = SocketAddr::new(KINDLE_IP_ADDRESS, KINDLE_SSH_PORT);
-    TcpStream::connect_timeout(&address, Duration::from_millis(KINDLE_CONNECT_TIMEOUT))
+pub fn open_tcp_connection(config: &KindleConfig) -> std::io::Result<TcpStream> {
+    let address = SocketAddr::new(config.ip_address, config.ssh_port);
+    TcpStream::connect_timeout(&address, Duration::from_millis(config.connect_timeout))
 }
 
-fn consume_io(channel: &mut Channel) -> std::io::Result<()> {
+/// Runs the command and reads everything it prints, stdout first since stderr is never more
+/// than a few lines
+fn run(session: &mut Session, command: &Command) -> Result<Output, CommandError> {
     use std::io::Read;
-    for b in channel.bytes() {
-        b?;
-    }
-    for b in channel.stderr().bytes() {
-        b?;
-    }
-    Ok(())
+    let cmd = command.to_shell();
+    let mut channel = session.channel_session()?;
+    debug!("{}", cmd);
+    channel.exec(&cmd)?;
+    let mut stdout = Vec::new();
+    channel.read_to_end(&mut stdout)?;
+    let mut stderr = String::new();
+    channel.stderr().read_to_string(&mut stderr)?;
+    channel.wait_eof()?;
+    channel.close()?;
+    channel.wait_close()?;
+    Ok(Output {
+        status: channel.exit_status()?,
+        stdout,
+        stderr,
+    })
 }
 
-pub fn open_ssh_session(tcp_stream: TcpStream) -> Result<Session, ssh2::Error> {
+pub fn open_ssh_session(tcp_stream: TcpStream, config: &KindleConfig) -> io::Result<Session> {
     let mut session = Session::new()?;
     session.set_tcp_stream(tcp_stream);
+    // Applies to every blocking call on the session from here on
+    session.set_timeout(config.command_timeout as u32);
     session.handshake()?;
-    session.userauth_password(KINDLE_USERNAME, KINDLE_PASSWORD)?;
+    verify_host_key(&session, config)?;
+    match config.auth {
+        SshAuth::Password => {
+            session.userauth_password(&config.username, config.password.expose())?
+        }
+        SshAuth::Key => session.userauth_pubkey_file(
+            &config.username,
+            None,
+            config
+                .private_key
+                .as_deref()
+                .unwrap_or_else(|| Path::new("")),
+            None,
+        )?,
+        SshAuth::Agent => session.userauth_agent(&config.username)?,
+    }
+    session.set_keepalive(false, config.keepalive_interval);
     Ok(session)
 }
 
-pub fn eips_show_image(
-    session: &mut Session,
-    png: &[u8],
-    full_update: bool,
-) -> Result<(), ssh2::Error> {
-    let remote_path = Path::new("/dev/shm/out.png");
-    debug!("scp {} kindle", remote_path.display());
-    let mut channel = session.scp_send(remote_path, 0o644, png.len() as u64, None)?;
-    channel.write_all(png).expect("failed to write png");
-    channel.close()?;
-    let mut channel = session.channel_session()?;
-    let cmd = if full_update {
-        "/usr/sbin/eips -f -g /dev/shm/out.png"
+/// Checks the Kindle's host key against the known hosts file, recording it the first time
+fn verify_host_key(session: &Session, config: &KindleConfig) -> io::Result<()> {
+    let (key, key_type) = session
+        .host_key()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Kindle sent no host key"))?;
+    let fingerprint = session
+        .host_key_hash(HashType::Sha256)
+        .map(|hash| {
+            let hex: Vec<String> = hash.iter().map(|byte| format!("{:02x}", byte)).collect();
+            hex.join(":")
+        })
+        .unwrap_or_default();
+    let mut known_hosts = session.known_hosts()?;
+    if config.known_hosts.exists() {
+        known_hosts.read_file(&config.known_hosts, KnownHostFileKind::OpenSSH)?;
+    }
+    // Entries for a non-standard port are written as [host]:port
+    let host = if config.ssh_port == 22 {
+        config.ip_address.to_string()
     } else {
-        "/usr/sbin/eips -g /dev/shm/out.png"
+        format!("[{}]:{}", config.ip_address, config.ssh_port)
     };
-    debug!("{}", cmd);
-    channel.exec(cmd)?;
-    consume_io(&mut channel).unwrap();
-    channel.wait_eof()?;
-    channel.close()?;
-    channel.wait_close()
+    match known_hosts.check_port(&config.ip_address.to_string(), config.ssh_port, key) {
+        CheckResult::Match => return Ok(()),
+        CheckResult::NotFound => info!(
+            "trusting Kindle host key SHA256 {} on first use, recording it in {}",
+            fingerprint,
+            config.known_hosts.display()
+        ),
+        CheckResult::Mismatch if config.accept_changed_host_key => {
+            warn!(
+                "Kindle host key changed to SHA256 {}, accepting it as configured",
+                fingerprint
+            );
+            for entry in known_hosts.hosts()? {
+                if entry.name() == Some(host.as_str()) {
+                    known_hosts.remove(&entry)?;
+                }
+            }
+        }
+        CheckResult::Mismatch => {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "Kindle host key changed to SHA256 {}, refusing to connect. \
+                     If this is expected, remove its entry from {} or set kindle.accept_changed_host_key",
+                    fingerprint,
+                    config.known_hosts.display()
+                ),
+            ))
+        }
+        CheckResult::Failure => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "failed to check Kindle host key",
+            ))
+        }
+    }
+    known_hosts.add(&host, key, "eink-clock", key_type.into())?;
+    known_hosts.write_file(&config.known_hosts, KnownHostFileKind::OpenSSH)?;
+    Ok(())
 }
 
-pub fn amixer_set_master_volume(session: &mut Session, volume: u8) -> Result<(), ssh2::Error> {
-    let mut channel = session.channel_session()?;
-    let cmd = format!("/usr/bin/amixer set Master {}%", volume);
-    debug!("{}", cmd);
-    channel.exec(&cmd)?;
-    consume_io(&mut channel).unwrap();
-    channel.wait_eof()?;
-    channel.close()?;
-    channel.wait_close()
+/// Sends a keepalive and runs a no-op, giving up on the session after `timeout` milliseconds
+pub fn session_is_alive(session: &mut Session, timeout: u32) -> bool {
+    let previous_timeout = session.timeout();
+    session.set_timeout(timeout);
+    let alive = session.keepalive_send().is_ok() && session.exec(&Command::new("true")).is_ok();
+    session.set_timeout(previous_timeout);
+    alive
 }
 
-pub fn aplay_audio_nonblocking(session: &mut Session, audio: &[u8]) -> Result<(), ssh2::Error> {
-    let remote_path = Path::new("/dev/shm/out.wav");
+fn scp_upload(session: &mut Session, remote_path: &Path, data: &[u8]) -> io::Result<()> {
     debug!("scp {} kindle", remote_path.display());
-    let mut channel = session.scp_send(remote_path, 0o644, audio.len() as u64, None)?;
-    channel.write_all(audio).expect("failed to write audio");
-    channel.close()?;
-    let mut channel = session.channel_session()?;
-    let cmd = "/usr/bin/aplay -q -N /dev/shm/out.wav";
-    debug!("{}", cmd);
-    channel.exec(cmd)?;
-    consume_io(&mut channel).unwrap();
-    channel.wait_eof()?;
-    channel.close()?;
-    channel.wait_close()
+    let mut channel = session.scp_send(remote_path, 0o644, data.len() as u64, None)?;
+    channel.write_all(data)?;
+    Ok(channel.close()?)
+}
+
+impl Shell for Session {
+    fn upload(&mut self, path: &Path, data: &[u8]) -> Result<(), CommandError> {
+        Ok(scp_upload(self, path, data)?)
+    }
+
+    fn run(&mut self, command: &Command) -> Result<Output, CommandError> {
+        run(self, command)
+    }
 }
@@ -0,0 +1,134 @@
+use std::fmt;
+use std::time::Duration;
+
+/// How the Kindle is doing, as far as it could tell. Anything it didn't report is `None`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Telemetry {
+    pub battery_percent: Option<u8>,
+    pub charging: Option<bool>,
+    /// Panel temperature in degrees Celsius, which e-ink waveforms depend on
+    pub temperature: Option<i32>,
+    pub uptime: Option<Duration>,
+    /// Free space in `/dev/shm`, where uploads go, in KiB
+    pub shm_free_kib: Option<u64>,
+}
+
+impl Telemetry {
+    /// Parses the `name=value` lines printed by [crate::command::telemetry_report]
+    pub fn parse(report: &str) -> Self {
+        let mut telemetry = Self::default();
+        for line in report.lines() {
+            let (name, value) = match line.split_once('=') {
+                Some((name, value)) => (name, value.trim()),
+                None => continue,
+            };
+            match name {
+                "battery" => telemetry.battery_percent = value.parse().ok(),
+                "charging" => {
+                    telemetry.charging = match value {
+                        "1" => Some(true),
+                        "0" => Some(false),
+                        _ => None,
+                    }
+                }
+                // Some kernels report millidegrees
+                "temperature" => {
+                    telemetry.temperature = value.parse::<i32>().ok().map(|temperature| {
+                        if temperature.abs() > 1000 {
+                            temperature / 1000
+                        } else {
+                            temperature
+                        }
+                    })
+                }
+                "uptime" => {
+                    telemetry.uptime = value.parse::<f64>().ok().map(Duration::from_secs_f64)
+                }
+                "shm_free" => telemetry.shm_free_kib = value.parse().ok(),
+                _ => {}
+            }
+        }
+        telemetry
+    }
+
+    /// Whether the battery is at or below `low_percent`, or running down because the Kindle
+    /// was unplugged. A full battery also stops charging, so that alone doesn't count
+    pub fn battery_needs_attention(&self, low_percent: u8) -> bool {
+        match self.battery_percent {
+            Some(percent) => {
+                percent <= low_percent || (self.charging == Some(false) && percent < 100)
+            }
+            None => false,
+        }
+    }
+}
+
+impl fmt::Display for Telemetry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn or_unknown<T: fmt::Display>(value: Option<T>) -> String {
+            value.map_or_else(|| "?".to_owned(), |value| value.to_string())
+        }
+        write!(
+            f,
+            "battery {}%{}, {}°C, up {} min, {} KiB free in /dev/shm",
+            or_unknown(self.battery_percent),
+            match self.charging {
+                Some(true) => " charging",
+                Some(false) => " not charging",
+                None => "",
+            },
+            or_unknown(self.temperature),
+            or_unknown(self.uptime.map(|uptime| uptime.as_secs() / 60)),
+            or_unknown(self.shm_free_kib)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_full_report() {
+        let telemetry = Telemetry::parse(
+            "battery=42\ncharging=0\ntemperature=23\nuptime=3600.50\nshm_free=1024\n",
+        );
+        assert_eq!(
+            telemetry,
+            Telemetry {
+                battery_percent: Some(42),
+                charging: Some(false),
+                temperature: Some(23),
+                uptime: Some(Duration::from_millis(3_600_500)),
+                shm_free_kib: Some(1024),
+            }
+        );
+    }
+
+    #[test]
+    fn millidegrees_are_scaled() {
+        assert_eq!(Telemetry::parse("temperature=23500").temperature, Some(23));
+    }
+
+    #[test]
+    fn missing_values_are_unknown() {
+        let telemetry =
+            Telemetry::parse("battery=\ncharging=\ntemperature=\nuptime=\nshm_free=\nnoise\n");
+        assert_eq!(telemetry, Telemetry::default());
+    }
+
+    #[test]
+    fn battery_needs_attention_when_low_or_unplugged() {
+        let battery = |percent, charging| Telemetry {
+            battery_percent: Some(percent),
+            charging: Some(charging),
+            ..Telemetry::default()
+        };
+        assert!(battery(15, true).battery_needs_attention(20));
+        assert!(battery(20, true).battery_needs_attention(20));
+        assert!(battery(50, false).battery_needs_attention(20));
+        assert!(!battery(50, true).battery_needs_attention(20));
+        assert!(!battery(100, false).battery_needs_attention(20));
+        assert!(!Telemetry::default().battery_needs_attention(20));
+    }
+}
@@ -0,0 +1,55 @@
+use cairo::{Context, LinearGradient};
+
+use crate::config::Config;
+use crate::display::Canvas;
+use crate::render::set_font;
+
+const TEXT_LADDER: &str = "The quick brown fox jumps over the lazy dog 0123456789";
+
+/// Step wedge, gradient and text ladder for tuning the calibration table by eye
+pub fn draw_test_pattern(ctx: &Context, canvas: &Canvas, config: &Config) {
+    let (width, height) = (canvas.width, canvas.height);
+    ctx.set_source_rgb(1.0, 1.0, 1.0);
+    ctx.paint().unwrap();
+
+    // One patch per calibration entry, labelled with its index
+    let band = height / 3.;
+    let patch_width = width / 16.;
+    set_font(ctx, &config.display.font);
+    ctx.set_font_size(canvas.unit * 0.15);
+    for i in 0..16 {
+        let gray = i as f64 / 15.;
+        ctx.set_source_rgb(gray, gray, gray);
+        ctx.rectangle(i as f64 * patch_width, 0., patch_width, band * 0.8);
+        ctx.fill().unwrap();
+
+        let label = i.to_string();
+        let extents = ctx.text_extents(&label).unwrap();
+        ctx.set_source_rgb(0., 0., 0.);
+        ctx.move_to(
+            (i as f64 + 0.5) * patch_width - extents.width / 2. - extents.x_bearing,
+            band * 0.95,
+        );
+        ctx.show_text(&label).unwrap();
+    }
+
+    let gradient = LinearGradient::new(0., 0., width, 0.);
+    gradient.add_color_stop_rgb(0., 0., 0., 0.);
+    gradient.add_color_stop_rgb(1., 1., 1., 1.);
+    ctx.set_source(&gradient).unwrap();
+    ctx.rectangle(0., band, width, band * 0.8);
+    ctx.fill().unwrap();
+
+    // Text from barely legible up to body size
+    ctx.set_source_rgb(0., 0., 0.);
+    let mut baseline = band * 2.;
+    let mut size = canvas.unit * 0.06;
+    while baseline + size < height {
+        ctx.set_font_size(size);
+        let extents = ctx.text_extents(TEXT_LADDER).unwrap();
+        baseline += extents.height * 1.2;
+        ctx.move_to(canvas.unit * 0.1, baseline);
+        ctx.show_text(TEXT_LADDER).unwrap();
+        size *= 1.25;
+    }
+}
@@ -1,11 +1,11 @@
-use crate::{KINDLE_PRODUCT_ID, KINDLE_VENDOR_ID};
+use crate::config::KindleConfig;
 
 use rusb::{Device, GlobalContext, Result};
 
-pub fn get_kindle() -> Result<Option<Device<GlobalContext>>> {
+pub fn get_kindle(config: &KindleConfig) -> Result<Option<Device<GlobalContext>>> {
     for device in rusb::devices()?.iter() {
         let desc = device.device_descriptor()?;
-        if desc.vendor_id() == KINDLE_VENDOR_ID && desc.product_id() == KINDLE_PRODUCT_ID {
+        if desc.vendor_id() == config.vendor_id && desc.product_id() == config.product_id {
             return Ok(Some(device));
         }
     }
@@ -0,0 +1,95 @@
+use serde::{Deserialize, Serialize};
+
+/// E-ink update modes, trading image quality for speed and flashing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Waveform {
+    /// Let the driver decide, for panels whose `eips` has no `-w` option
+    Auto,
+    /// All 16 grays, slowest and the only one that fully clears ghosting
+    Gc16,
+    /// Black and white only, quick and without flashing
+    Du,
+    /// Black and white only, fastest but ghosts the most
+    A2,
+}
+
+impl Waveform {
+    /// Value for `eips -w`, if one should be passed
+    pub fn eips_name(self) -> Option<&'static str> {
+        match self {
+            Waveform::Auto => None,
+            Waveform::Gc16 => Some("gc16"),
+            Waveform::Du => Some("du"),
+            Waveform::A2 => Some("a2"),
+        }
+    }
+
+    /// Value for `fbink -W`, which understands all of them
+    pub fn fbink_name(self) -> &'static str {
+        match self {
+            Waveform::Auto => "AUTO",
+            Waveform::Gc16 => "GC16",
+            Waveform::Du => "DU",
+            Waveform::A2 => "A2",
+        }
+    }
+}
+
+/// What a part of the frame shows, from least to most demanding
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Content {
+    /// The minute digits, which change every update and have to be quick
+    Digits,
+    /// Plain text
+    Text,
+    /// Art, weather icons and anything else with grays in it
+    Graphics,
+}
+
+/// Part of the canvas and what is drawn in it, reported by the renderer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Zone {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+    pub content: Content,
+}
+
+/// Waveform to use for each kind of content
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Waveforms {
+    pub digits: Waveform,
+    pub text: Waveform,
+    pub graphics: Waveform,
+}
+
+impl Waveforms {
+    pub fn for_content(&self, content: Content) -> Waveform {
+        match content {
+            Content::Digits => self.digits,
+            Content::Text => self.text,
+            Content::Graphics => self.graphics,
+        }
+    }
+}
+
+/// Replaces the profile's waveform for any kind of content that is set
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WaveformOverrides {
+    pub digits: Option<Waveform>,
+    pub text: Option<Waveform>,
+    pub graphics: Option<Waveform>,
+}
+
+impl WaveformOverrides {
+    pub fn apply(&self, defaults: Waveforms) -> Waveforms {
+        Waveforms {
+            digits: self.digits.unwrap_or(defaults.digits),
+            text: self.text.unwrap_or(defaults.text),
+            graphics: self.graphics.unwrap_or(defaults.graphics),
+        }
+    }
+}
@@ -1,11 +1,11 @@
 use metar::{Metar, MetarError};
 
-use crate::WEATHER_STATION;
+use crate::config::WeatherConfig;
 
-pub async fn get_current_metar_data() -> reqwest::Result<String> {
+pub async fn get_current_metar_data(config: &WeatherConfig) -> reqwest::Result<String> {
     let url = format!(
         "https://www.aviationweather.gov/adds/dataserver_current/httpparam?datasource=metars&requesttype=retrieve&format=xml&hoursBeforeNow=1.25&mostRecentForEachStation=constraint&stationString={}",
-        WEATHER_STATION
+        config.station
     );
 
     reqwest::get(url).await?.text().await